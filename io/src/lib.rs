@@ -1,12 +1,12 @@
 #![no_std]
 
 use gmeta::{In, InOut, Metadata};
-use gstd::{prelude::*, ActorId};
+use gstd::{collections::BTreeMap, prelude::*, ActorId};
 pub struct MarketMetadata;
 
 impl Metadata for MarketMetadata {
     type Init = In<Config>;
-    type Handle = InOut<MarketAction, Result<MarketEvent, MarketError>>;
+    type Handle = InOut<MarketAction, Result<SequencedEvent, MarketError>>;
     type Others = ();
     type Reply = ();
     type Signal = ();
@@ -16,24 +16,322 @@ impl Metadata for MarketMetadata {
 #[derive(Debug, Encode, Decode, TypeInfo, Clone)]
 pub struct Config {
     pub public_key: String,
+    /// When set, product names are matched ignoring ASCII case, so "Shoe" and "shoe" are the
+    /// same product. Should be chosen before the catalog is filled: products listed under a
+    /// mixed-case key while the flag was off can't be reached once it is turned on.
+    pub case_insensitive_names: bool,
+    /// Upper bound on the cumulative amount a single actor may spend in the market.
+    pub max_spend_per_actor: Option<u128>,
+    /// Upper bound on the value the market holds for others at any time, i.e. proceeds not yet
+    /// paid out, including those of orders that may still be refunded.
+    pub max_contract_value: Option<u128>,
+    /// How many blocks after purchase a buyer may still cancel an order for a refund.
+    pub refund_window_blocks: u64,
+    /// Sends the admin a `MarketEvent::UnauthorizedAttempt` whenever someone else tries a
+    /// privileged action.
+    pub log_unauthorized: bool,
+    /// How many stock movements are kept per product; zero disables the history.
+    pub max_history_len: u32,
+    /// Minimum number of blocks between two inventory snapshots; zero disables them.
+    pub snapshot_interval_blocks: u64,
+    /// Whether value attached to `Buy` beyond the total is returned to the buyer. When unset, the
+    /// excess is kept as a tip for the seller and isn't refunded if the order is cancelled.
+    pub refund_overpayment: bool,
+    /// Program sent an `InventoryUpdate` whenever a product is listed or restocked.
+    pub inventory_oracle: Option<ActorId>,
+    /// Has `Buy` send the oracle an `InventoryQuery` for each product it takes units of and wait
+    /// for the reply before committing. Requires `inventory_oracle`.
+    pub confirm_stock_with_oracle: bool,
+    /// Gas attached to messages carrying value, for recipients that are programs and need gas to
+    /// accept it.
+    pub value_send_gas: u64,
+    /// Delists products, other than preorder ones, whose stock is sold out by a purchase.
+    pub auto_delist_on_zero: bool,
+    /// Most items a state query may ask for in one request.
+    pub max_query_batch: u32,
+    /// Most items a batch action may carry, so it fails early rather than running out of gas.
+    pub max_batch_size: u32,
+    /// Blanks `ProductData::cost` in every state reply other than `StateReply::MarginReport`.
+    pub hide_cost: bool,
+    /// Replies to every successful `Buy` with `MarketEvent::Receipt`, whatever its `verbose` flag.
+    pub buy_receipts: bool,
+    /// Symbol front-ends show next to amounts, at most 8 bytes.
+    pub currency_symbol: String,
+    /// Digits of an amount after the decimal point when displayed, at most 30.
+    pub decimals: u8,
+    /// Most attributes a product may have.
+    pub max_attributes: u32,
+    /// Most bytes in an attribute's key or value.
+    pub max_attribute_len: u32,
+    /// Most bytes in a purchase's tracking number.
+    pub max_tracking_len: u32,
+    /// Blocks after `AddProduct` before the product can be bought, so that everyone gets to see
+    /// a new listing before it sells out.
+    pub listing_delay_blocks: u64,
+    /// Most bytes in the contact a buyer may leave on `Buy`.
+    pub max_contact_len: u32,
+    /// Reports on each successful reply the gas its action burnt, see `SequencedEvent::gas_used`.
+    pub include_gas_diagnostics: bool,
+    /// Units `RestockProduct` quantities must be a multiple of, e.g. 12 for goods sold by the
+    /// dozen. Can't be zero.
+    pub restock_increment: Option<u128>,
+    /// Value a buyer pays per loyalty point earned; zero disables earning points. Each point
+    /// redeemed takes one unit of value off a later purchase.
+    pub points_divisor: u128,
+    /// Orders kept per buyer; past it, the oldest delivered, cancelled or failed ones are dropped
+    /// and the buyer is sent `HistoryTrimmed`. Active orders are always kept.
+    pub max_history_per_buyer: Option<u32>,
+    /// (order age in blocks, refund basis points) pairs with strictly increasing ages: an order
+    /// cancelled at most that many blocks after purchase gets the share of the first pair it
+    /// fits, scaled by the product's `refund_percent`, and nothing past the last one. Empty
+    /// means a full share for the whole refund window.
+    pub refund_tiers: Vec<(u64, u16)>,
+    /// Blocks a `ReserveWithDeposit` reservation is held before its deposit is forfeited; `None`
+    /// turns reservations off. Can't be zero.
+    pub reservation_blocks: Option<u32>,
+    /// Least deposit a reservation takes, in basis points of its price at reservation time.
+    pub min_deposit_percent: u16,
+    /// Most orders a buyer may have that are neither delivered, cancelled nor failed.
+    pub max_open_orders: Option<u32>,
+    /// How amounts that don't divide evenly are rounded: totals of products with a `unit_scale`,
+    /// partial refund shares and least reservation deposits.
+    pub rounding: RoundingMode,
+    /// How `Buy` assigns order ids.
+    pub order_id_mode: OrderIdMode,
 }
 
-#[derive(Debug, Encode, Decode, TypeInfo)]
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            public_key: String::new(),
+            case_insensitive_names: false,
+            max_spend_per_actor: None,
+            max_contract_value: None,
+            refund_window_blocks: 0,
+            log_unauthorized: false,
+            max_history_len: 0,
+            snapshot_interval_blocks: 0,
+            refund_overpayment: true,
+            inventory_oracle: None,
+            confirm_stock_with_oracle: false,
+            value_send_gas: 0,
+            auto_delist_on_zero: false,
+            max_query_batch: 100,
+            max_batch_size: 50,
+            hide_cost: false,
+            buy_receipts: false,
+            rounding: RoundingMode::Floor,
+            order_id_mode: OrderIdMode::Sequential,
+            max_attributes: 16,
+            max_attribute_len: 64,
+            max_tracking_len: 64,
+            listing_delay_blocks: 0,
+            max_contact_len: 128,
+            include_gas_diagnostics: false,
+            restock_increment: None,
+            points_divisor: 0,
+            max_history_per_buyer: None,
+            refund_tiers: Vec::new(),
+            reservation_blocks: None,
+            min_deposit_percent: 0,
+            max_open_orders: None,
+            currency_symbol: String::new(),
+            decimals: 0,
+        }
+    }
+}
+
+/// `Sequential` ids count up from zero, so an order's id tells anyone how many orders came before
+/// it. `Hashed` ids are derived from the buyer, the block, the private order count and a seed from
+/// `exec::random`, which makes them unguessable, at the cost of a hash per purchase and of ids that
+/// no longer sort by age. `State::next_order_id` then reads zero rather than give the count away.
+#[derive(Debug, Encode, Decode, TypeInfo, Clone, Copy, PartialEq, Eq)]
+pub enum OrderIdMode {
+    Sequential,
+    Hashed,
+}
+
+/// Rounding of proportional amounts. For an amount charged to the buyer, such as a fee, `Floor`
+/// favours the buyer and `Ceil` the party collecting it; for an amount taken off the price, such
+/// as a discount, it's the other way round. `Nearest` rounds halves up.
+#[derive(Debug, Encode, Decode, TypeInfo, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    Floor,
+    Ceil,
+    Nearest,
+}
+
+impl RoundingMode {
+    /// `value * numerator / denominator`, rounded in this mode, or `None` on overflow or a zero
+    /// `denominator`.
+    pub fn mul_div(self, value: u128, numerator: u128, denominator: u128) -> Option<u128> {
+        let product = value.checked_mul(numerator)?;
+        let quotient = product.checked_div(denominator)?;
+        let remainder = product % denominator;
+        let round_up = match self {
+            Self::Floor => false,
+            Self::Ceil => remainder != 0,
+            Self::Nearest => remainder >= denominator - remainder,
+        };
+        if round_up {
+            quotient.checked_add(1)
+        } else {
+            Some(quotient)
+        }
+    }
+}
+
+#[derive(Debug, Encode, Decode, TypeInfo, Clone)]
 pub struct ProductData {
+    /// The name as it was given on `AddProduct`, which may differ in case from the product key.
+    pub display_name: String,
     pub quantity: u128,
     pub price: u128,
+    /// Lifetime cap on the units that may ever be put on sale.
+    pub max_supply: Option<u128>,
+    /// Units put on sale so far, counting the initial stock and every restock.
+    pub total_minted: u128,
+    pub category: Option<String>,
+    /// Whether buyers may order more than is in stock, waiting for a restock to fill the order.
+    pub preorder: bool,
+    /// Frozen products can't be bought, alone or in a bundle, but existing orders can still be
+    /// cancelled.
+    pub frozen: bool,
+    /// Block of the latest change to any of the product's fields, including its stock.
+    pub last_modified: u64,
+    /// Identifier from the seller's own inventory system, unique across the market. Empty when
+    /// the product has none.
+    pub sku: String,
+    /// What the seller paid per unit, never charged to buyers.
+    pub cost: Option<u128>,
+    /// Units sold and not cancelled, counting bundle components and preorders.
+    pub units_sold: u128,
+    pub flash_sale: Option<FlashSale>,
+    /// Display name in each language, by language code; `display_name` is the default.
+    pub translations: BTreeMap<String, String>,
+    /// Country codes the product ships to; `None` means everywhere.
+    pub allowed_countries: Option<Vec<String>>,
+    /// Inactive products are left out of `GetProducts`, `GetProductsPage` and
+    /// `GetProductsSortedBy`. Set again when stock is added.
+    pub active: bool,
+    /// Blocks a buyer has to wait between two purchases of the product, alone or in a bundle.
+    pub cooldown_blocks: Option<u64>,
+    /// Free-form (key, value) pairs such as color or size, in the order they were first set.
+    pub attributes: Vec<(String, String)>,
+    /// Block timestamp from which the product can no longer be bought, for perishable goods.
+    pub expires_at: Option<u64>,
+    /// Block at which the product was added.
+    pub listed_at: u64,
+    /// Bulk discounts, sorted by `min_quantity`, no two with the same one.
+    pub price_tiers: Vec<PriceTier>,
+    /// Share, in basis points, of the paid value and store credit that `CancelOrder` gives back;
+    /// the rest stays with the seller. Orders of bundles are refunded in full.
+    pub refund_percent: u16,
+    /// Buyers the product is reserved for while a gated drop runs; `None` means anyone.
+    pub allowlist: Option<Vec<ActorId>>,
+    /// Digital goods, delivered as soon as they're bought with no shipping step.
+    pub instant: bool,
+    /// Sent to each buyer of an instant product, e.g. a download link. It's public, like the rest
+    /// of the state.
+    pub delivery_payload: Option<String>,
+    /// Stock units making up one priced unit, for goods sold by weight or length: with 1000, the
+    /// quantity is counted in grams and `price` is per kilogram. Purchases cost
+    /// `price * quantity / unit_scale`, rounded by `Config::rounding`. Never zero.
+    pub unit_scale: u128,
+}
+/// Unit price charged for purchases of at least `min_quantity` units. Of the tiers a purchase
+/// qualifies for, and the regular or flash sale price, the lowest applies.
+#[derive(Debug, Encode, Decode, TypeInfo, Clone, PartialEq, Eq)]
+pub struct PriceTier {
+    pub min_quantity: u128,
+    pub unit_price: u128,
+}
+/// Temporary price of a product, charged instead of `ProductData::price` until `ends_at`.
+#[derive(Debug, Encode, Decode, TypeInfo, Clone)]
+pub struct FlashSale {
+    pub sale_price: u128,
+    /// First block at which the regular price applies again.
+    pub ends_at: u64,
+}
+/// Stock set aside for a buyer against a deposit, see `MarketAction::ReserveWithDeposit`.
+#[derive(Debug, Encode, Decode, TypeInfo, Clone)]
+pub struct Reservation {
+    pub buyer: ActorId,
+    /// Key of the reserved product or bundle.
+    pub name: String,
+    pub quantity: u128,
+    pub deposit: u128,
+    /// First block at which the deposit may be forfeited.
+    pub expires_at: u64,
+    pub delivery_address: DeliveryAddress,
+    /// Price of the reserved units when they were reserved.
+    pub total: u128,
+}
+/// Sent to `Config::inventory_oracle` with the stock a product has after being listed or restocked.
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct InventoryUpdate {
+    pub name: String,
+    pub quantity: u128,
+}
+/// Sent to `Config::inventory_oracle` by `Buy` when `Config::confirm_stock_with_oracle` is set.
+/// The oracle replies with the number of units of the product it holds, as a `u128`.
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct InventoryQuery {
+    pub name: String,
+}
+
+#[derive(Debug, Encode, Decode, TypeInfo, Clone)]
+pub struct BundleData {
+    /// Product keys and how many units of each one bundle contains.
+    pub components: Vec<(String, u128)>,
+    pub price: u128,
 }
 #[derive(Debug, Encode, Decode, TypeInfo, Clone)]
 pub struct PurchaseData {
+    pub order_id: u128,
+    /// Key of the bought product or bundle.
     pub name: String,
     pub quantity: u128,
     pub status: Status,
-    pub delivery_address: String,
+    pub delivery_address: DeliveryAddress,
+    /// Opaque to the market; left for the seller by the buyer.
+    pub contact: Option<String>,
+    /// Block height at which the order was placed.
+    pub block: u64,
+    pub timestamp: u64,
+    /// Price of one unit when the order was placed.
+    pub unit_price: u128,
+    /// Value kept by the market for the order, i.e. without the returned change and the store
+    /// credit spent.
+    pub total_paid: u128,
+    /// Store credit spent on the order, given back if it's cancelled.
+    pub credit_used: u128,
+    /// Loyalty points spent on the order, given back if it's cancelled.
+    pub points_redeemed: u128,
+    /// Loyalty points the order earned, taken back if it's cancelled.
+    pub points_earned: u128,
+    /// Carrier tracking number, attached when the order is shipped.
+    pub tracking_number: Option<String>,
+    /// Every status the order has been in, with the block it entered it at, oldest first.
+    pub status_history: Vec<(u64, Status)>,
+}
+#[derive(Debug, Encode, Decode, TypeInfo, Clone)]
+pub struct DeliveryAddress {
+    /// Country code, matched against `ProductData::allowed_countries` ignoring ASCII case.
+    pub country: String,
+    /// The rest of the address, free-form.
+    pub details: String,
 }
 #[derive(Debug, Encode, Decode, TypeInfo, Clone)]
 pub enum Status {
     PaidFor,
-    // TransmittedForDelivery and etc
+    Cancelled,
+    Shipped,
+    Delivered,
+    /// Paid for a preorder product that had no stock for it yet.
+    Preordered,
+    /// Couldn't be delivered, and was refunded and restocked.
+    DeliveryFailed,
 }
 
 #[derive(Encode, Decode, TypeInfo)]
@@ -42,6 +340,12 @@ pub enum MarketAction {
         name: String,
         quantity: u128,
         price: u128,
+        max_supply: Option<u128>,
+        preorder: bool,
+        sku: String,
+        /// Instant products can't take preorders; only they can have a `delivery_payload`.
+        instant: bool,
+        delivery_payload: Option<String>,
     },
     UpdateProductInfo {
         name: String,
@@ -57,8 +361,270 @@ pub enum MarketAction {
     Buy {
         name: String,
         quantity: u128,
-        delivery_address: String,
+        delivery_address: DeliveryAddress,
+        /// How the seller can reach the buyer about the delivery, e.g. an email address.
+        contact: Option<String>,
+        /// Fails with `PriceAboveMax` if the unit price has risen above this by the time the
+        /// purchase is handled.
+        max_unit_price: Option<u128>,
+        /// Fails with `InexactPayment` unless the attached value is exactly the total, so no change
+        /// has to be sent back.
+        exact: bool,
+        /// Pays as much of the total as possible with the caller's store credit, so only the rest
+        /// has to be attached.
+        use_credit: bool,
+        /// Loyalty points to spend on what store credit hasn't covered; fails with
+        /// `InsufficientPoints` if the caller has fewer. Only as many as needed are spent.
+        redeem_points: u128,
+        /// Replies with the full `Bought` event when set and with `OrderPlaced` otherwise.
+        verbose: bool,
+    },
+    /// Sends the proceeds accumulated for `seller` in one transfer, keeping back those of orders
+    /// still inside the refund window.
+    Payout {
+        seller: ActorId,
+    },
+    /// Lists a bundle whose purchase takes `components` (product name, units per bundle) from
+    /// stock in one go.
+    AddBundle {
+        name: String,
+        components: Vec<(String, u128)>,
+        price: u128,
+    },
+    /// Cancels one of the caller's orders that hasn't been shipped yet and refunds it, as allowed
+    /// by the product's `refund_percent`, as long as the refund window hasn't closed.
+    CancelOrder {
+        order_id: u128,
+    },
+    /// Adds `quantity` units to the product's stock, serving queued preorders first.
+    RestockProduct {
+        name: String,
+        quantity: u128,
+    },
+    /// Same as `UpdateProductInfo` setting both fields, but only applied while the product still
+    /// has the expected quantity and price.
+    CompareAndUpdateProduct {
+        name: String,
+        expected_quantity: u128,
+        expected_price: u128,
+        new_quantity: u128,
+        new_price: u128,
+    },
+    /// Puts the product into `category`, or takes it out of any category when `None`.
+    SetProductCategory {
+        name: String,
+        category: Option<String>,
+    },
+    /// Moves an order along its lifecycle: `PaidFor` -> `Shipped` -> `Delivered`. A tracking
+    /// number may only be given along with `Shipped`.
+    UpdatePurchaseStatus {
+        order_id: u128,
+        status: Status,
+        tracking_number: Option<String>,
+    },
+    /// Removes the caller's delivered, cancelled and undeliverable orders from the market.
+    ForgetMyHistory,
+    FreezeProduct {
+        name: String,
+        frozen: bool,
+    },
+    /// Deletes every listed product or bundle that exists.
+    DeleteProductsBatch(Vec<String>),
+    /// Records what the seller paid per unit of the product, for margin reporting.
+    SetProductCost {
+        name: String,
+        cost: Option<u128>,
+    },
+    /// Sells the product at `sale_price`, which must be below its price, for the next
+    /// `duration_blocks` blocks.
+    StartFlashSale {
+        name: String,
+        sale_price: u128,
+        duration_blocks: u32,
+    },
+    /// Sent by the market to itself when a flash sale is over; the admin may also send it to end
+    /// a sale early.
+    EndFlashSale {
+        name: String,
+    },
+    /// Lists the products and bundles whose price, flash sale price or any tier unit price is
+    /// below the current existential deposit, e.g. after the network raised it.
+    RevalidatePrices,
+    /// Sets the product's display name in `lang`, or removes that translation when `translation`
+    /// is empty.
+    SetProductTranslation {
+        name: String,
+        lang: String,
+        translation: String,
+    },
+    /// Sets every product's stock to `quantity`, or adds `quantity` to it when `delta` is set.
+    /// Nothing changes if any product would exceed its max supply.
+    RestockAll {
+        quantity: u128,
+        delta: bool,
+    },
+    /// Restricts the product to buyers delivering to one of `countries`; `None` or an empty list
+    /// lifts the restriction.
+    SetAllowedCountries {
+        name: String,
+        countries: Option<Vec<String>>,
+    },
+    /// Gives one of the caller's orders that hasn't been shipped yet to `to`, who may then cancel
+    /// it for the refund. `to` mustn't be blocked and needs room under `Config::max_open_orders`.
+    /// What was paid for the order moves from the caller's `State::total_spent` to `to`'s.
+    TransferPurchase {
+        order_id: u128,
+        to: ActorId,
+    },
+    SetPurchaseCooldown {
+        name: String,
+        cooldown_blocks: Option<u64>,
+    },
+    /// Asks to be sent `MarketEvent::BackInStock` the next time the sold-out product has stock
+    /// again, whether restocked, updated, merged into or returned by a cancelled order or an
+    /// expired reservation.
+    SubscribeRestock {
+        name: String,
     },
+    UnsubscribeRestock {
+        name: String,
+    },
+    /// Sets the value of one of the product's attributes, adding it if it's new.
+    SetProductAttribute {
+        name: String,
+        key: String,
+        value: String,
+    },
+    RemoveProductAttribute {
+        name: String,
+        key: String,
+    },
+    /// Applies each legal status update, reporting the failing ones instead of giving up.
+    UpdatePurchaseStatusBatch(Vec<(u128, Status)>),
+    /// Sends `to` whatever the market's balance holds beyond what it owes sellers, such as value
+    /// sent to it by mistake.
+    SweepUnaccountedFunds {
+        to: ActorId,
+    },
+    /// Gives `actor` store credit worth `amount`, which `Buy` can spend in place of value.
+    GrantCredit {
+        actor: ActorId,
+        amount: u128,
+    },
+    SetProductExpiry {
+        name: String,
+        expires_at: Option<u64>,
+    },
+    /// Stops `actor` from buying or subscribing to restocks until unblocked.
+    BlockActor {
+        actor: ActorId,
+    },
+    UnblockActor {
+        actor: ActorId,
+    },
+    /// Moves the stock of product `from` into product `into`, which must be another product,
+    /// points the purchases and bundles of `from` at `into` and deletes `from`.
+    MergeProducts {
+        from: String,
+        into: String,
+    },
+    /// Refunds and restocks a paid or shipped order that couldn't be delivered.
+    MarkDeliveryFailed {
+        order_id: u128,
+    },
+    /// Replaces the product's price tiers; an empty list removes them.
+    SetPriceTiers {
+        name: String,
+        tiers: Vec<PriceTier>,
+    },
+    /// At most 10000 basis points, i.e. a full refund; 0 makes the product non-refundable.
+    SetRefundPercent {
+        name: String,
+        refund_percent: u16,
+    },
+    /// Buys each (product or bundle name, quantity) line as an order of its own, all of them or
+    /// none. Fails with `CartItemUnavailable` on the first line that the stock, after the lines
+    /// before it, can't cover.
+    BuyCart {
+        items: Vec<(String, u128)>,
+        delivery_address: DeliveryAddress,
+    },
+    /// Replaces the featured products with the listed ones, in that order.
+    SetFeatured(Vec<String>),
+    /// Features a product after the ones already featured.
+    AddFeatured {
+        name: String,
+    },
+    RemoveFeatured {
+        name: String,
+    },
+    /// Adds a product, neither capped nor preordered, that only the `allowlist` buyers can buy
+    /// until `LiftAllowlist`. An empty list adds it ungated.
+    AddAllowlistedProduct {
+        name: String,
+        quantity: u128,
+        price: u128,
+        allowlist: Vec<ActorId>,
+    },
+    /// Opens an allowlisted product to every buyer.
+    LiftAllowlist {
+        name: String,
+    },
+    /// Moves every product in category `from` to category `into`.
+    RenameCategory {
+        from: String,
+        into: String,
+    },
+    /// Sets `ProductData::unit_scale`, which can't be zero or more than the product's price.
+    /// Purchases whose total comes to less than the existential deposit are refused.
+    SetUnitScale {
+        name: String,
+        unit_scale: u128,
+    },
+    /// Takes `quantity` units out of stock for the caller against `deposit`, which must be
+    /// attached, non-zero and at least `Config::min_deposit_percent` of the price. The purchase
+    /// checks and caps of `Buy` apply, and reservations count as open orders. Unless completed
+    /// within `Config::reservation_blocks`, the units go back on sale and the seller keeps the
+    /// deposit.
+    ReserveWithDeposit {
+        name: String,
+        quantity: u128,
+        deposit: u128,
+        delivery_address: DeliveryAddress,
+    },
+    /// Buys the reserved units at the current price, with the deposit counting towards it and
+    /// the attached value covering the rest. Replies and fails like `Buy`.
+    CompleteReservation {
+        reservation_id: u128,
+    },
+    /// Forfeits the deposit of an expired reservation; sent by the market itself when it expires.
+    ForfeitReservation {
+        reservation_id: u128,
+    },
+    /// Changes the product's price to `new_price` from `effective_block` on, replacing any change
+    /// already scheduled for that block. A price below the existential deposit by then is dropped.
+    SchedulePriceChange {
+        name: String,
+        new_price: u128,
+        effective_block: u64,
+    },
+    /// Gives up one of the caller's reservations, putting its units back on sale. The deposit is
+    /// returned if the reservation can no longer be completed for its `total`, e.g. because the
+    /// product was frozen, expired or made dearer; otherwise the seller keeps it.
+    CancelReservation {
+        reservation_id: u128,
+    },
+}
+
+/// An event with its position among all events the market has emitted, as replies or as
+/// notifications, starting from 1. A gap in `seq` means an event was missed.
+#[derive(Encode, Decode, TypeInfo)]
+pub struct SequencedEvent {
+    pub seq: u64,
+    pub event: MarketEvent,
+    /// Gas burnt from loading the action to replying to it, if `Config::include_gas_diagnostics`
+    /// is set. Only replies carry it; it leaves out the cost of sending the reply itself.
+    pub gas_used: Option<u64>,
 }
 
 #[derive(Encode, Decode, TypeInfo)]
@@ -67,6 +633,10 @@ pub enum MarketEvent {
         name: String,
         quantity: u128,
         price: u128,
+        max_supply: Option<u128>,
+        preorder: bool,
+        sku: String,
+        instant: bool,
     },
     ProductInfoUpdated {
         name: String,
@@ -83,10 +653,222 @@ pub enum MarketEvent {
         buyer: ActorId,
         name: String,
         quantity: u128,
+        order_id: u128,
+        /// Overpayment kept for the seller when `Config::refund_overpayment` is unset.
+        tip: u128,
+    },
+    PayoutSent {
+        seller: ActorId,
+        amount: u128,
+    },
+    BundleAdded {
+        name: String,
+        components: Vec<(String, u128)>,
+        price: u128,
+    },
+    OrderCancelled {
+        order_id: u128,
+        refund: u128,
+        /// Share of the order refunded, in basis points.
+        refund_percent: u16,
+    },
+    ProductRestocked {
+        name: String,
+        quantity: u128,
+    },
+    /// Sent to the admin, not replied, when `who` is refused the privileged `action`.
+    UnauthorizedAttempt {
+        who: ActorId,
+        action: String,
+    },
+    ProductCategorySet {
+        name: String,
+        category: Option<String>,
+    },
+    PurchaseStatusUpdated {
+        order_id: u128,
+        status: Status,
+    },
+    HistoryForgotten {
+        removed_count: u32,
+    },
+    /// Minimal reply to a successful non-verbose `Buy`.
+    OrderPlaced {
+        order_id: u128,
+    },
+    ProductFreezeUpdated {
+        name: String,
+        frozen: bool,
+    },
+    ProductsBatchDeleted {
+        deleted: Vec<String>,
+        not_found: Vec<String>,
+    },
+    ProductCostSet {
+        name: String,
+        cost: Option<u128>,
+    },
+    FlashSaleStarted {
+        name: String,
+        sale_price: u128,
+        ends_at: u64,
+    },
+    FlashSaleEnded {
+        name: String,
+    },
+    Receipt(Receipt),
+    /// Sorted keys of the products and bundles found by `RevalidatePrices`.
+    PricesNeedingAttention(Vec<String>),
+    ProductTranslationSet {
+        name: String,
+        lang: String,
+        translation: String,
+    },
+    AllRestocked {
+        product_count: u32,
+    },
+    AllowedCountriesSet {
+        name: String,
+        countries: Option<Vec<String>>,
+    },
+    PurchaseTransferred {
+        order_id: u128,
+        from: ActorId,
+        to: ActorId,
+    },
+    /// Sent to the admin, not replied, when a purchase sold out the product and
+    /// `Config::auto_delist_on_zero` is set.
+    ProductAutoDelisted {
+        name: String,
+    },
+    PurchaseCooldownSet {
+        name: String,
+        cooldown_blocks: Option<u64>,
+    },
+    RestockSubscribed {
+        name: String,
+    },
+    RestockUnsubscribed {
+        name: String,
+    },
+    /// Sent, not replied, to the actors subscribed to the product's restock.
+    BackInStock {
+        name: String,
+    },
+    ProductAttributeSet {
+        name: String,
+        key: String,
+        value: String,
+    },
+    ProductAttributeRemoved {
+        name: String,
+        key: String,
+    },
+    StatusBatchUpdated {
+        succeeded: Vec<u128>,
+        failed: Vec<(u128, MarketError)>,
+    },
+    FundsSwept {
+        to: ActorId,
+        amount: u128,
+    },
+    CreditGranted {
+        actor: ActorId,
+        amount: u128,
+        /// The actor's credit after the grant.
+        balance: u128,
+    },
+    /// Sent to the buyer, not replied, when a purchase spends some of their store credit.
+    CreditUsed {
+        order_id: u128,
+        amount: u128,
+        /// The buyer's credit left.
+        balance: u128,
+    },
+    ProductExpirySet {
+        name: String,
+        expires_at: Option<u64>,
+    },
+    ActorBlocked {
+        actor: ActorId,
+    },
+    ActorUnblocked {
+        actor: ActorId,
+    },
+    ProductsMerged {
+        from: String,
+        into: String,
+        /// Units of `from` added to the stock of `into`.
+        quantity: u128,
+    },
+    DeliveryFailedRefunded {
+        order_id: u128,
+        buyer: ActorId,
+        refund: u128,
+    },
+    PriceTiersSet {
+        name: String,
+        tiers: Vec<PriceTier>,
+    },
+    RefundPercentSet {
+        name: String,
+        refund_percent: u16,
+    },
+    CartBought {
+        /// One order per cart line, in the same order.
+        order_ids: Vec<u128>,
+        total: u128,
+    },
+    /// Keys of the featured products, in order.
+    FeaturedSet(Vec<String>),
+    /// Sent to a buyer whose finished orders were dropped to honour `max_history_per_buyer`.
+    HistoryTrimmed {
+        removed_count: u32,
+    },
+    AllowlistedProductAdded {
+        name: String,
+        quantity: u128,
+        price: u128,
+        allowlist: Vec<ActorId>,
+    },
+    AllowlistLifted {
+        name: String,
+    },
+    /// Sent to the buyer of an instant product, whose order is already `Delivered`.
+    InstantlyDelivered {
+        order_id: u128,
+        delivery_payload: Option<String>,
+    },
+    CategoryRenamed {
+        from: String,
+        into: String,
+        updated_count: u32,
+    },
+    UnitScaleSet {
+        name: String,
+        unit_scale: u128,
+    },
+    Reserved {
+        reservation_id: u128,
+        reservation: Reservation,
+    },
+    DepositForfeited {
+        reservation_id: u128,
+        buyer: ActorId,
+        deposit: u128,
+    },
+    PriceChangeScheduled {
+        name: String,
+        new_price: u128,
+        effective_block: u64,
+    },
+    ReservationCancelled {
+        reservation_id: u128,
+        refund: u128,
     },
 }
 
-#[derive(Encode, Decode, TypeInfo)]
+#[derive(Debug, Encode, Decode, TypeInfo)]
 pub enum MarketError {
     NotAdmin,
     AlreadyExists,
@@ -95,28 +877,406 @@ pub enum MarketError {
     PriceLessThanExistentialDeposit,
     InsufficientValue,
     QuantityExceeded,
+    NothingToPayout,
+    InvalidConfig,
+    SpendCapExceeded,
+    BundleComponentUnavailable,
+    ThereIsNoSuchOrder,
+    OrderNotCancellable,
+    RefundWindowClosed,
+    ProceedsAlreadyPaidOut,
+    MaxSupplyExceeded,
+    ConcurrentModification,
+    InvalidCategory,
+    IllegalStatusTransition,
+    ProductFrozen,
+    DuplicateSku,
+    InvalidFlashSale,
+    FlashSaleActive,
+    NoFlashSale,
+    InvalidTranslation,
+    NotDeliverableToRegion,
+    /// A purchase would have taken a product's stock below zero.
+    InventoryInvariantViolated,
+    OrderNotTransferable,
+    /// More items than `Config::max_batch_size` in a batch action.
+    BatchTooLarge,
+    PriceAboveMax,
+    PurchaseCooldownActive,
+    ContractValueCapReached,
+    TooManySubscribers,
+    InexactPayment,
+    InvalidAttribute,
+    TooManyAttributes,
+    ThereIsNoSuchAttribute,
+    NothingToSweep,
+    /// The market's balance is less than what it owes, so nothing can be swept.
+    BalanceBelowObligations,
+    InvalidTrackingNumber,
+    ProductExpired,
+    ActorBlocked,
+    ProductNotYetBuyable,
+    InvalidContact,
+    /// `MergeProducts` given the same product twice.
+    SameProduct,
+    DuplicateTierThreshold,
+    TooManyPriceTiers,
+    InvalidRefundPercent,
+    InvalidRestockIncrement,
+    CartItemUnavailable {
+        name: String,
+    },
+    TooManyFeatured,
+    InsufficientPoints,
+    NotOnAllowlist,
+    InvalidDeliveryPayload,
+    InstantPreorder,
+    InvalidUnitScale,
+    DepositTooLow,
+    ReservationsDisabled,
+    ThereIsNoSuchReservation,
+    ReservationNotExpired,
+    TooManyOpenOrders,
+    EffectiveBlockPassed,
+    TooManyReservations,
+    /// The inventory oracle couldn't be asked, or didn't reply in time.
+    OracleUnavailable,
+    /// The inventory oracle holds fewer units than the purchase takes.
+    OracleStockMismatch,
+}
+
+#[derive(Debug, Encode, Decode, TypeInfo, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    /// The product key.
+    Name,
+    /// The price currently charged, i.e. the flash sale price while a sale is on.
+    Price,
+    Quantity,
+    UnitsSold,
 }
 
 #[derive(Encode, Decode, TypeInfo)]
 pub enum StateQuery {
     All,
+    /// Products sorted by key, so clients can look one up with a binary search. Large catalogs
+    /// are truncated; use `GetProductsPage` to read them whole.
     GetProducts,
+    /// Purchases sorted by buyer, and each buyer's by `order_id`, truncated like `GetProducts`.
+    /// The order is canonical, so replies can be hashed or diffed as they are.
     GetPurchases,
     GetActorPurchases(ActorId),
+    GetStateSize,
+    GetProductCount,
+    /// Everything a front-end needs to know about how an actor relates to the market.
+    GetActorContext(ActorId),
+    /// Prices a purchase exactly as `MarketAction::Buy` would, failing with the same error.
+    QuoteBuy {
+        name: String,
+        quantity: u128,
+    },
+    /// Recent stock movements of a product, oldest first.
+    GetStockHistory(String),
+    /// Sorted, deduplicated categories of all products.
+    GetCategories,
+    /// Products changed, and names of products deleted, at or after the given block.
+    GetProductsModifiedSince(u64),
+    /// Proceeds waiting to be paid out to a seller.
+    GetSellerBalance(ActorId),
+    GetProductBySku(String),
+    /// Purchases of each listed actor that has any. Only the first `Config::max_query_batch`
+    /// actors are looked up, with `truncated` set if more were listed.
+    GetPurchasesForActors(Vec<ActorId>),
+    /// Revenue and cost of the units sold of every product with a known cost.
+    GetMarginReport,
+    /// The product with its `display_name` in `lang`, if it has a translation for it.
+    GetProduct {
+        name: String,
+        lang: Option<String>,
+    },
+    /// Up to `limit` products in the order of `GetProducts`, skipping the first `offset`.
+    GetProductsPage {
+        offset: u32,
+        limit: u32,
+    },
+    /// Up to `limit` buyers' purchases in the order of `GetPurchases`, skipping the first `offset`.
+    GetPurchasesPage {
+        offset: u32,
+        limit: u32,
+    },
+    /// `seq` of the latest event emitted, or 0 if there is none yet.
+    GetEventSeq,
+    /// The latest `limit` inventory snapshots, oldest first.
+    GetInventorySnapshots {
+        limit: u32,
+    },
+    /// Purchases of products or bundles that have since been deleted, by buyer, truncated like
+    /// `GetPurchases`.
+    GetOrphanedPurchases,
+    GetConfig,
+    /// Invoice of an order still kept in the market, built from its purchase data.
+    GetInvoice(u128),
+    /// The latest `limit` privileged actions the admin carried out, oldest first.
+    GetAdminActions {
+        limit: u32,
+    },
+    /// Each named product in the given order. Only the first `Config::max_query_batch` names are
+    /// looked up, with `truncated` set if more were given.
+    CompareProducts(Vec<String>),
+    /// Like `GetProductsPage`, with the products sorted by `field` instead of key. Products that
+    /// tie are in key order.
+    GetProductsSortedBy {
+        field: SortField,
+        descending: bool,
+        offset: u32,
+        limit: u32,
+    },
+    /// Sorted keys of the products past their `expires_at`, for cleaning up.
+    GetExpiredProducts,
+    IsBlocked(ActorId),
+    /// Totals over the products in a category; all zero for a category with no products.
+    GetCategoryStats(String),
+    /// Number of actors with purchases in the market, leaving out the ones whose every order was
+    /// cancelled or failed delivery if `exclude_cancelled` is set.
+    GetBuyerCount {
+        exclude_cancelled: bool,
+    },
+    /// Products, listed or not, with exactly this many units in stock, sorted and truncated like
+    /// `GetProducts`.
+    GetProductsWithQuantity(u128),
+    GetBalanceSheet,
+    /// Everything the market keeps about an actor, in one reply.
+    ExportActorData(ActorId),
+    GetFeatured,
+    GetLoyaltyPoints(ActorId),
+    /// Sorted keys of the products with no units sold, cancelled orders not counting.
+    GetUnsoldProducts,
+    /// Like `QuoteBuy`, also telling the stock each product would be left with. Checks that
+    /// depend on the buyer, such as cooldowns or spend caps, aren't simulated.
+    SimulateBuy {
+        name: String,
+        quantity: u128,
+    },
+    /// `PurchaseData::status_history` of an order still kept in the market.
+    GetOrderTimeline(u128),
+    GetReservation(u128),
+    /// Price changes still to take effect, as (product key, effective block, new price), soonest
+    /// first.
+    GetScheduledPriceChanges,
+    /// Reservations of the buyer not yet expired, as (reservation id, reservation), oldest first,
+    /// so a front-end can remind them to complete checkout before `expires_at`.
+    GetActiveReservations(ActorId),
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Encode, Decode, TypeInfo)]
 pub enum StateReply {
     All(State),
-    Products(Vec<(String, ProductData)>),
-    Purchases(Vec<(ActorId, Vec<PurchaseData>)>),
-    ActorPurchases(Option<Vec<PurchaseData>>),
+    /// `truncated` is set when there are more products after the returned ones.
+    Products {
+        products: Vec<(String, ProductData)>,
+        truncated: bool,
+    },
+    /// `truncated` is set when there are more buyers after the returned ones.
+    Purchases {
+        purchases: Vec<(ActorId, Vec<PurchaseData>)>,
+        truncated: bool,
+    },
+    ActorPurchases(ActorPurchases),
+    /// Approximate SCALE-encoded sizes, in bytes, of the product and purchase collections.
+    StateSize {
+        products: u64,
+        purchases: u64,
+    },
+    Count(u32),
+    ActorContext(ActorContext),
+    Quote(Result<Quote, MarketError>),
+    /// (block, signed change in units) pairs; sales are negative, restocks positive.
+    StockHistory(Vec<(u64, i128)>),
+    Categories(Vec<String>),
+    ProductsModifiedSince {
+        products: Vec<(String, ProductData)>,
+        deleted: Vec<String>,
+    },
+    Balance(u128),
+    /// Product key and data.
+    Product(Option<(String, ProductData)>),
+    /// `None` if the totals overflow.
+    MarginReport(Option<MarginReport>),
+    EventSeq(u64),
+    /// (block, total units in stock across all products) pairs.
+    InventorySnapshots(Vec<(u64, u128)>),
+    Config(Config),
+    Invoice(Option<Invoice>),
+    /// (block, admin, action name) triples.
+    AdminActions(Vec<(u64, ActorId, String)>),
+    /// `None` for each name that isn't a product.
+    ProductComparison {
+        products: Vec<Option<ProductData>>,
+        truncated: bool,
+    },
+    ExpiredProducts(Vec<String>),
+    Blocked(bool),
+    /// `None` if the totals overflow.
+    CategoryStats(Option<CategoryStats>),
+    BalanceSheet(BalanceSheet),
+    ActorExport(ActorExport),
+    /// Featured products, in order.
+    Featured(Vec<(String, ProductData)>),
+    LoyaltyPoints(u128),
+    UnsoldProducts(Vec<String>),
+    BuySimulation(Result<BuySimulation, MarketError>),
+    OrderTimeline(Option<Vec<(u64, Status)>>),
+    Reservation(Option<Reservation>),
+    ScheduledPriceChanges(Vec<(String, u64, u128)>),
+    Reservations(Vec<(u128, Reservation)>),
 }
 
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct Quote {
+    pub unit_price: u128,
+    /// The exact value to attach to `MarketAction::Buy`.
+    pub total: u128,
+}
+
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct BuySimulation {
+    /// Value the purchase would cost.
+    pub total_payment: u128,
+    /// Stock each product, or each component of a bundle, would be left with; preordered units
+    /// don't take it below zero.
+    pub quantities_left: Vec<(String, u128)>,
+}
+
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct ActorPurchases {
+    /// Whether the actor has orders kept, or has paid for any even if they were all dropped since.
+    pub known: bool,
+    pub purchases: Vec<PurchaseData>,
+}
+
+/// Proof of purchase a buyer can keep without relying on an indexer.
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct Receipt {
+    pub order_id: u128,
+    pub buyer: ActorId,
+    /// Key of the bought product or bundle.
+    pub name: String,
+    pub unit_price: u128,
+    pub quantity: u128,
+    pub total_paid: u128,
+    /// Value returned to the buyer.
+    pub change: u128,
+    /// Overpayment kept for the seller.
+    pub tip: u128,
+    pub block: u64,
+    pub timestamp: u64,
+    /// BLAKE2b-256 of the SCALE encoding of all the fields above, in order.
+    pub hash: [u8; 32],
+}
+
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct Invoice {
+    pub order_id: u128,
+    pub buyer: ActorId,
+    pub seller: ActorId,
+    pub lines: Vec<InvoiceLine>,
+    pub subtotal: u128,
+    pub total: u128,
+    pub block: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct InvoiceLine {
+    /// Key of the bought product or bundle.
+    pub name: String,
+    pub quantity: u128,
+    pub unit_price: u128,
+    pub total: u128,
+}
+
+/// Totals over the products with a known cost, valuing sold units at the current price.
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct MarginReport {
+    pub revenue: u128,
+    pub cost: u128,
+    /// `revenue` minus `cost`.
+    pub margin: i128,
+}
+
+/// Totals over the products of one category, valuing sold units at the current price like
+/// `MarginReport`.
+#[derive(Debug, Encode, Decode, TypeInfo, Default, PartialEq, Eq)]
+pub struct CategoryStats {
+    pub product_count: u32,
+    pub total_stock: u128,
+    pub total_units_sold: u128,
+    pub total_revenue: u128,
+}
+
+/// The market's balance against what it owes. Refunds are sent as soon as an order is cancelled,
+/// so none are ever pending.
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct BalanceSheet {
+    pub contract_balance: u128,
+    /// Proceeds owed to sellers.
+    pub pending_payouts: u128,
+    /// Deposits of reservations that are neither completed nor forfeited yet.
+    pub deposits: u128,
+    /// Value attached to purchases still waiting on the inventory oracle.
+    pub pending_buys: u128,
+    /// Part of `pending_payouts` paid for orders their buyers may still cancel for a refund.
+    pub escrowed: u128,
+    /// `contract_balance` beyond `pending_payouts`, `deposits` and `pending_buys`, e.g. value sent
+    /// by mistake; zero if the balance falls short.
+    pub unaccounted: u128,
+}
+
+/// Version of the `ActorExport` layout, bumped whenever a field of it, or of a type it contains,
+/// changes.
+pub const ACTOR_EXPORT_VERSION: u16 = 4;
+
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct ActorExport {
+    /// `ACTOR_EXPORT_VERSION` of the program that built the export, encoded first.
+    pub version: u16,
+    pub actor: ActorId,
+    pub purchases: Vec<PurchaseData>,
+    pub total_spent: u128,
+    /// Store credit left.
+    pub credit: u128,
+    /// Loyalty points left.
+    pub loyalty_points: u128,
+    /// Sorted keys of the products the actor waits to be told are back in stock.
+    pub restock_subscriptions: Vec<String>,
+    pub blocked: bool,
+}
+
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct ActorContext {
+    pub is_admin: bool,
+    pub purchase_count: u32,
+    pub total_spent: u128,
+}
+
+/// Version of the `State` layout, bumped whenever a field of it, or of a type it contains, changes.
+pub const STATE_SCHEMA_VERSION: u16 = 17;
+
 #[derive(Debug, Encode, Decode, TypeInfo)]
 pub struct State {
+    /// `STATE_SCHEMA_VERSION` of the program that built the state. It's encoded first, so a
+    /// client can read it before deciding how to decode the rest.
+    pub schema_version: u16,
     pub products: Vec<(String, ProductData)>,
     pub purchases: Vec<(ActorId, Vec<PurchaseData>)>,
+    pub bundles: Vec<(String, BundleData)>,
     pub admin: ActorId,
     pub config: Config,
+    pub pending_payouts: Vec<(ActorId, u128)>,
+    pub total_spent: Vec<(ActorId, u128)>,
+    /// Orders placed so far, or zero under `OrderIdMode::Hashed`.
+    pub next_order_id: u128,
+    /// Unset when `products` and `purchases` had to be cut short, keeping the first entries by key,
+    /// to fit the reply; the paginated queries return them whole.
+    pub complete: bool,
 }
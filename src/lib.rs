@@ -1,225 +1,3561 @@
 #![no_std]
-use gstd::{collections::HashMap, exec, msg, prelude::*, ActorId};
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use core::cmp::Ordering;
+use gstd::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    exec, msg,
+    prelude::*,
+    ActorId,
+};
 use market_io::*;
 
+const MAX_CATEGORY_LEN: usize = 64;
+const MAX_LANG_LEN: usize = 16;
+/// Most entries a state reply lists, to keep it under the size the node accepts.
+const MAX_REPLY_ITEMS: u32 = 1000;
+/// Most bytes the encoded `State` may take in a reply to `StateQuery::All`.
+const MAX_STATE_REPLY_SIZE: usize = 1 << 20;
+const MAX_SNAPSHOTS: usize = 500;
+const MAX_RESTOCK_SUBSCRIBERS: usize = 100;
+const MAX_ADMIN_ACTIONS: usize = 500;
+const MAX_PRICE_TIERS: usize = 16;
+const MAX_REFUND_TIERS: usize = 16;
+const FULL_REFUND_PERCENT: u16 = 10_000;
+const MAX_FEATURED: usize = 50;
+const MAX_DELIVERY_PAYLOAD_LEN: usize = 1024;
+const MAX_VALUE_SEND_GAS: u64 = 10_000_000_000;
+const MAX_CURRENCY_SYMBOL_LEN: usize = 8;
+const MAX_DECIMALS: u8 = 30;
+/// Gas set aside for handling the inventory oracle's reply.
+const ORACLE_REPLY_DEPOSIT: u64 = 1_000_000_000;
+/// Blocks a purchase waits for the inventory oracle before failing with `OracleUnavailable`.
+const ORACLE_REPLY_BLOCKS: u32 = 10;
+/// Gas reserved for the message that ends a flash sale.
+const FLASH_SALE_END_GAS: u64 = 5_000_000_000;
+/// Gas reserved for the message that forfeits an expired reservation.
+const RESERVATION_FORFEIT_GAS: u64 = 5_000_000_000;
+const MAX_RESERVATIONS_PER_BUYER: usize = 10;
+
 struct Market {
     products: HashMap<String, ProductData>,
     purchases: HashMap<ActorId, Vec<PurchaseData>>,
+    /// Composite products, keyed like `products`, whose purchase takes stock from their components.
+    bundles: HashMap<String, BundleData>,
     admin: ActorId,
     config: Config,
+    /// Seller proceeds that have been earned but not yet sent.
+    pending_payouts: HashMap<ActorId, u128>,
+    /// Cumulative amount each actor has paid for their purchases.
+    total_spent: HashMap<ActorId, u128>,
+    next_order_id: u128,
+    /// Recent stock movements of each product as (block, signed change in units).
+    stock_history: HashMap<String, Vec<(u64, i128)>>,
+    /// Buyer of each order still kept in `purchases`.
+    order_index: HashMap<u128, ActorId>,
+    /// Ids of orders waiting for stock of a preorder product, oldest first.
+    preorders: HashMap<String, VecDeque<u128>>,
+    /// Block at which each deleted product was removed, for clients syncing the catalog.
+    deleted_products: HashMap<String, u64>,
+    /// Key of the product listed under each SKU.
+    sku_index: HashMap<String, String>,
+    /// `seq` of the latest emitted event.
+    event_seq: u64,
+    /// Total stock after purchases and restocks, as (block, units), at most one per
+    /// `Config::snapshot_interval_blocks`.
+    snapshots: Vec<(u64, u128)>,
+    /// Block of each buyer's latest purchase of each product with a purchase cooldown.
+    last_buy: HashMap<(ActorId, String), u64>,
+    /// Actors to notify when each sold-out product is restocked.
+    restock_subscribers: HashMap<String, Vec<ActorId>>,
+    /// Privileged actions the admin has been let through, as (block, admin, action name), oldest
+    /// first and at most `MAX_ADMIN_ACTIONS`.
+    admin_actions: Vec<(u64, ActorId, String)>,
+    /// Action the current message was admitted to by `check_admin`, logged once it succeeds.
+    admitted_action: Option<(ActorId, String)>,
+    /// Store credit granted by the admin, spendable on purchases in place of value.
+    credits: HashMap<ActorId, u128>,
+    /// Actors the admin has blocked from buying, mapped to `true`; unblocking removes the entry.
+    blocklist: HashMap<ActorId, bool>,
+    /// Keys of the products storefronts highlight, in order.
+    featured: Vec<String>,
+    /// Loyalty points each buyer has earned and not yet redeemed.
+    loyalty_points: HashMap<ActorId, u128>,
+    /// Stock held against deposits, by reservation id.
+    reservations: HashMap<u128, Reservation>,
+    next_reservation_id: u128,
+    /// New price each product takes from a block on, by (effective block, product key).
+    price_changes: BTreeMap<(u64, String), u128>,
+    /// Value attached to purchases waiting on the inventory oracle, to be bought with or returned.
+    pending_buy_value: u128,
+}
+
+/// How a validated purchase is carried out, see `Market::validate_buy`.
+struct BuyPlan {
+    /// Key of the product or bundle.
+    key: String,
+    unit_price: u128,
+    /// Units taken from each product.
+    stock_lines: Vec<(String, u128)>,
+    /// Purchase cooldowns of the products, restarted by the purchase.
+    cooldowns: Vec<(String, u64)>,
+    /// Whether the order joins the preorder queue instead of taking stock.
+    preordered: bool,
+    /// Part of the price paid with the attached value.
+    value_due: u128,
+    /// Attached value beyond `value_due`.
+    change: u128,
+    /// Buyer's total spend including this purchase.
+    spent: u128,
+    /// Store credit the buyer is left with.
+    credit_balance: u128,
+    credit_used: u128,
+    /// Loyalty points the buyer is left with, after redeeming and earning.
+    points_balance: u128,
+    points_redeemed: u128,
+    points_earned: u128,
 }
 
 static mut MARKET: Option<Market> = None;
 
 #[no_mangle]
 extern "C" fn init() {
-    let config = msg::load().expect("Unable to decode `Config`.");
+    let config: Config = msg::load().expect("Unable to decode `Config`.");
+    let admin = msg::source();
+    if admin == ActorId::zero() {
+        panic!("The market can't be initialized from the zero address.");
+    }
+    validate_config(&config).expect("Invalid `Config`.");
     unsafe {
         MARKET = Some(Market {
-            admin: msg::source(),
+            admin,
             config,
             products: HashMap::new(),
             purchases: HashMap::new(),
+            bundles: HashMap::new(),
+            pending_payouts: HashMap::new(),
+            total_spent: HashMap::new(),
+            next_order_id: 0,
+            stock_history: HashMap::new(),
+            order_index: HashMap::new(),
+            preorders: HashMap::new(),
+            deleted_products: HashMap::new(),
+            sku_index: HashMap::new(),
+            event_seq: 0,
+            snapshots: Vec::new(),
+            last_buy: HashMap::new(),
+            restock_subscribers: HashMap::new(),
+            admin_actions: Vec::new(),
+            admitted_action: None,
+            credits: HashMap::new(),
+            blocklist: HashMap::new(),
+            featured: Vec::new(),
+            loyalty_points: HashMap::new(),
+            reservations: HashMap::new(),
+            next_reservation_id: 0,
+            price_changes: BTreeMap::new(),
+            pending_buy_value: 0,
         })
     };
 }
 
-impl Market {
-    fn add_product(
+impl Market {
+    /// Returns the key under which the product called `name` is stored in `self.products`.
+    fn product_key(&self, name: &str) -> String {
+        if self.config.case_insensitive_names {
+            name.to_ascii_lowercase()
+        } else {
+            name.to_string()
+        }
+    }
+    /// Fails with `NotAdmin` unless the current message comes from the admin, notifying the admin
+    /// of the attempt if `Config::log_unauthorized` is set. Admitted actions go to the audit log
+    /// once they succeed, see `log_admin_action`.
+    fn check_admin(&mut self, action: &str) -> Result<(), MarketError> {
+        let msg_source = msg::source();
+        if msg_source == self.admin {
+            self.admitted_action = Some((msg_source, action.to_string()));
+            return Ok(());
+        }
+        if self.config.log_unauthorized {
+            let notification = MarketEvent::UnauthorizedAttempt {
+                who: msg_source,
+                action: action.to_string(),
+            };
+            let notification = self.sequence(notification);
+            msg::send(self.admin, notification, 0).expect("Error in sending a notification");
+        }
+        Err(MarketError::NotAdmin)
+    }
+    /// Adds the action admitted for the current message to the audit log if it `succeeded`.
+    fn log_admin_action(&mut self, succeeded: bool) {
+        let Some((admin, action)) = self.admitted_action.take() else {
+            return;
+        };
+        if !succeeded {
+            return;
+        }
+        if self.admin_actions.len() >= MAX_ADMIN_ACTIONS {
+            self.admin_actions
+                .drain(..=self.admin_actions.len() - MAX_ADMIN_ACTIONS);
+        }
+        self.admin_actions
+            .push((exec::block_height().into(), admin, action));
+    }
+    fn check_not_blocked(&self, actor: ActorId) -> Result<(), MarketError> {
+        if self.blocklist.get(&actor).copied().unwrap_or_default() {
+            return Err(MarketError::ActorBlocked);
+        }
+        Ok(())
+    }
+    /// Checks that `buyer` may place `new_orders` more orders under `Config::max_open_orders`.
+    fn check_open_orders(&self, buyer: ActorId, new_orders: usize) -> Result<(), MarketError> {
+        let Some(max) = self.config.max_open_orders else {
+            return Ok(());
+        };
+        // Reserved units are as good as ordered.
+        let reserved = self.reservations_of(buyer);
+        let open = self.purchases.get(&buyer).map_or(reserved, |purchases| {
+            purchases
+                .iter()
+                .filter(|purchase| {
+                    matches!(
+                        purchase.status,
+                        Status::Preordered | Status::PaidFor | Status::Shipped
+                    )
+                })
+                .count()
+                + reserved
+        });
+        if open.saturating_add(new_orders) > max as usize {
+            return Err(MarketError::TooManyOpenOrders);
+        }
+        Ok(())
+    }
+    fn reservations_of(&self, buyer: ActorId) -> usize {
+        self.reservations
+            .values()
+            .filter(|reservation| reservation.buyer == buyer)
+            .count()
+    }
+    fn check_batch_size(&self, len: usize) -> Result<(), MarketError> {
+        if len > self.config.max_batch_size as usize {
+            return Err(MarketError::BatchTooLarge);
+        }
+        Ok(())
+    }
+    /// Stamps the next sequence number on an event about to be replied or sent.
+    fn sequence(&mut self, event: MarketEvent) -> SequencedEvent {
+        self.event_seq += 1;
+        SequencedEvent {
+            seq: self.event_seq,
+            event,
+            gas_used: None,
+        }
+    }
+    /// Returns the unit price of the product or bundle stored under `key`, together with the
+    /// stock that buying `quantity` of it takes from each product.
+    fn stock_lines(
+        &self,
+        key: &str,
+        quantity: u128,
+    ) -> Result<(u128, Vec<(String, u128)>), MarketError> {
+        if let Some(product_data) = self.products.get(key) {
+            return Ok((
+                unit_price(product_data, quantity),
+                vec![(key.to_string(), quantity)],
+            ));
+        }
+        let bundle = self
+            .bundles
+            .get(key)
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+        let stock_lines = bundle
+            .components
+            .iter()
+            .map(|(product_name, per_bundle)| {
+                per_bundle
+                    .checked_mul(quantity)
+                    .map(|needed| (product_name.clone(), needed))
+            })
+            .collect::<Option<_>>()
+            .ok_or(MarketError::BundleComponentUnavailable)?;
+        Ok((bundle.price, stock_lines))
+    }
+    /// Appends a stock movement to the product's history, evicting the oldest entries beyond
+    /// `Config::max_history_len`.
+    fn record_stock_change(&mut self, key: &str, delta: i128) {
+        let max_len = self.config.max_history_len as usize;
+        if max_len == 0 || delta == 0 {
+            return;
+        }
+        let history = self.stock_history.entry(key.to_string()).or_default();
+        if history.len() >= max_len {
+            history.drain(..=history.len() - max_len);
+        }
+        history.push((exec::block_height().into(), delta));
+    }
+    /// Records the total stock if a snapshot interval has passed since the latest snapshot,
+    /// evicting the oldest snapshots beyond `MAX_SNAPSHOTS`.
+    fn snapshot_inventory(&mut self) {
+        let interval = self.config.snapshot_interval_blocks;
+        let current_block: u64 = exec::block_height().into();
+        if interval == 0
+            || self
+                .snapshots
+                .last()
+                .is_some_and(|(block, _)| current_block < block.saturating_add(interval))
+        {
+            return;
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS {
+            self.snapshots
+                .drain(..=self.snapshots.len() - MAX_SNAPSHOTS);
+        }
+        let stock = self.products.values().fold(0u128, |stock, product_data| {
+            stock.saturating_add(product_data.quantity)
+        });
+        self.snapshots.push((current_block, stock));
+    }
+    /// Mutable access to a product that marks it as modified in the current block.
+    fn product_mut(&mut self, key: &str) -> Option<&mut ProductData> {
+        let product_data = self.products.get_mut(key)?;
+        product_data.last_modified = exec::block_height().into();
+        Some(product_data)
+    }
+    /// Serves queued preorders of the product from its stock, oldest first, stopping at the first
+    /// one the stock can't fully cover.
+    fn fill_preorders(&mut self, key: &str) {
+        let Some(queue) = self.preorders.get_mut(key) else {
+            return;
+        };
+        let Some(product_data) = self.products.get_mut(key) else {
+            return;
+        };
+        let mut history = Vec::new();
+        while let Some(order_id) = queue.front().copied() {
+            let purchase = self.order_index.get(&order_id).and_then(|buyer| {
+                self.purchases
+                    .get_mut(buyer)?
+                    .iter_mut()
+                    .find(|purchase| purchase.order_id == order_id)
+            });
+            // Cancelled or forgotten preorders are dropped from the queue.
+            let Some(purchase) =
+                purchase.filter(|purchase| matches!(purchase.status, Status::Preordered))
+            else {
+                queue.pop_front();
+                continue;
+            };
+            if purchase.quantity > product_data.quantity {
+                break;
+            }
+            product_data.quantity -= purchase.quantity;
+            product_data.last_modified = exec::block_height().into();
+            set_status(purchase, Status::PaidFor);
+            history.push(stock_delta(purchase.quantity, 0));
+            queue.pop_front();
+        }
+        if queue.is_empty() {
+            self.preorders.remove(key);
+        }
+        for delta in history {
+            self.record_stock_change(key, delta);
+        }
+    }
+    /// Relists the product if it has stock again and serves its queued preorders. If it had sold
+    /// out at `previous` and the preorders left some stock, its restock subscribers are told.
+    fn stock_added(&mut self, key: &str, previous: u128) {
+        if let Some(product_data) = self.products.get_mut(key) {
+            product_data.active |= product_data.quantity != 0;
+        }
+        self.fill_preorders(key);
+        let stock = self
+            .products
+            .get(key)
+            .map_or(0, |product_data| product_data.quantity);
+        if previous == 0 && stock != 0 {
+            for subscriber in self.restock_subscribers.remove(key).unwrap_or_default() {
+                let notification = self.sequence(MarketEvent::BackInStock {
+                    name: key.to_string(),
+                });
+                msg::send(subscriber, notification, 0).expect("Error in sending a notification");
+            }
+        }
+    }
+    fn notify_inventory_oracle(&self, key: String, quantity: u128) {
+        if let Some(oracle) = self.config.inventory_oracle {
+            let update = InventoryUpdate {
+                name: key,
+                quantity,
+            };
+            msg::send(oracle, update, 0).expect("Error in sending an inventory update");
+        }
+    }
+    /// Asks the inventory oracle, if `Config::confirm_stock_with_oracle` is set, whether it holds
+    /// the units that buying `quantity` of `name` takes. Preorder products aren't asked about,
+    /// as they may be bought out of stock.
+    async fn confirm_stock_with_oracle(
+        &self,
+        name: &str,
+        quantity: u128,
+    ) -> Result<(), MarketError> {
+        let Some(oracle) = self
+            .config
+            .inventory_oracle
+            .filter(|_| self.config.confirm_stock_with_oracle)
+        else {
+            return Ok(());
+        };
+        let (_, stock_lines) = self.stock_lines(&self.product_key(name), quantity)?;
+        for (product_name, needed) in stock_lines {
+            if self
+                .products
+                .get(&product_name)
+                .map_or(true, |product_data| product_data.preorder)
+            {
+                continue;
+            }
+            let query = InventoryQuery { name: product_name };
+            let stock: u128 = msg::send_for_reply_as(oracle, query, 0, ORACLE_REPLY_DEPOSIT)
+                .and_then(|reply| reply.up_to(Some(ORACLE_REPLY_BLOCKS)))
+                .map_err(|_| MarketError::OracleUnavailable)?
+                .await
+                .map_err(|_| MarketError::OracleUnavailable)?;
+            if stock < needed {
+                return Err(MarketError::OracleStockMismatch);
+            }
+        }
+        Ok(())
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn add_product(
+        &mut self,
+        name: String,
+        quantity: u128,
+        price: u128,
+        max_supply: Option<u128>,
+        preorder: bool,
+        sku: String,
+        instant: bool,
+        delivery_payload: Option<String>,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("AddProduct")?;
+        self.insert_product(
+            name,
+            quantity,
+            price,
+            max_supply,
+            preorder,
+            sku,
+            instant,
+            delivery_payload,
+        )
+    }
+    /// Lists a new product for `add_product` and `add_allowlisted_product`, leaving the admin
+    /// check to them.
+    #[allow(clippy::too_many_arguments)]
+    fn insert_product(
+        &mut self,
+        name: String,
+        quantity: u128,
+        price: u128,
+        max_supply: Option<u128>,
+        preorder: bool,
+        sku: String,
+        instant: bool,
+        delivery_payload: Option<String>,
+    ) -> Result<MarketEvent, MarketError> {
+        let key = self.product_key(&name);
+        if self.products.contains_key(&key) || self.bundles.contains_key(&key) {
+            return Err(MarketError::AlreadyExists);
+        }
+        if price < exec::env_vars().existential_deposit {
+            return Err(MarketError::PriceLessThanExistentialDeposit);
+        }
+        if max_supply.is_some_and(|max_supply| quantity > max_supply) {
+            return Err(MarketError::MaxSupplyExceeded);
+        }
+        if !sku.is_empty() && self.sku_index.contains_key(&sku) {
+            return Err(MarketError::DuplicateSku);
+        }
+        if instant && preorder {
+            return Err(MarketError::InstantPreorder);
+        }
+        if delivery_payload.as_ref().is_some_and(|delivery_payload| {
+            !instant
+                || delivery_payload.is_empty()
+                || delivery_payload.len() > MAX_DELIVERY_PAYLOAD_LEN
+        }) {
+            return Err(MarketError::InvalidDeliveryPayload);
+        }
+
+        let product_data = ProductData {
+            display_name: name.clone(),
+            quantity,
+            price,
+            max_supply,
+            total_minted: quantity,
+            category: None,
+            preorder,
+            frozen: false,
+            last_modified: exec::block_height().into(),
+            sku: sku.clone(),
+            cost: None,
+            units_sold: 0,
+            flash_sale: None,
+            translations: BTreeMap::new(),
+            allowed_countries: None,
+            active: true,
+            cooldown_blocks: None,
+            attributes: Vec::new(),
+            expires_at: None,
+            listed_at: exec::block_height().into(),
+            price_tiers: Vec::new(),
+            refund_percent: FULL_REFUND_PERCENT,
+            allowlist: None,
+            instant,
+            delivery_payload,
+            unit_scale: 1,
+        };
+        if !sku.is_empty() {
+            self.sku_index.insert(sku.clone(), key.clone());
+        }
+        self.products.insert(key.clone(), product_data);
+        self.deleted_products.remove(&key);
+        self.record_stock_change(&key, stock_delta(0, quantity));
+        self.notify_inventory_oracle(key, quantity);
+
+        Ok(MarketEvent::ProductAdded {
+            name,
+            quantity,
+            price,
+            max_supply,
+            preorder,
+            sku,
+            instant,
+        })
+    }
+    fn restock_product(
+        &mut self,
+        name: String,
+        quantity: u128,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("RestockProduct")?;
+        if quantity == 0 {
+            return Err(MarketError::ZeroQuantity);
+        }
+        if self
+            .config
+            .restock_increment
+            .is_some_and(|increment| quantity % increment != 0)
+        {
+            return Err(MarketError::InvalidRestockIncrement);
+        }
+        let key = self.product_key(&name);
+        let product_data = self
+            .product_mut(&key)
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        mint(product_data, quantity)?;
+        let previous_stock = product_data.quantity;
+        product_data.quantity += quantity;
+        self.record_stock_change(&key, stock_delta(0, quantity));
+        self.stock_added(&key, previous_stock);
+        let stock = self
+            .products
+            .get(&key)
+            .map_or(0, |product_data| product_data.quantity);
+        self.notify_inventory_oracle(key, stock);
+        self.snapshot_inventory();
+
+        Ok(MarketEvent::ProductRestocked { name, quantity })
+    }
+    fn subscribe_restock(
+        &mut self,
+        msg_source: ActorId,
+        name: String,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_not_blocked(msg_source)?;
+        let key = self.product_key(&name);
+        if !self.products.contains_key(&key) {
+            return Err(MarketError::ThereIsNoSuchName);
+        }
+        let subscribers = self.restock_subscribers.entry(key).or_default();
+        if !subscribers.contains(&msg_source) {
+            if subscribers.len() >= MAX_RESTOCK_SUBSCRIBERS {
+                return Err(MarketError::TooManySubscribers);
+            }
+            subscribers.push(msg_source);
+        }
+
+        Ok(MarketEvent::RestockSubscribed { name })
+    }
+    fn unsubscribe_restock(
+        &mut self,
+        msg_source: ActorId,
+        name: String,
+    ) -> Result<MarketEvent, MarketError> {
+        let key = self.product_key(&name);
+        if let Some(subscribers) = self.restock_subscribers.get_mut(&key) {
+            subscribers.retain(|subscriber| *subscriber != msg_source);
+            if subscribers.is_empty() {
+                self.restock_subscribers.remove(&key);
+            }
+        }
+
+        Ok(MarketEvent::RestockUnsubscribed { name })
+    }
+    fn restock_all(&mut self, quantity: u128, delta: bool) -> Result<MarketEvent, MarketError> {
+        self.check_admin("RestockAll")?;
+
+        // Every product is checked before any is changed, so a failure leaves the stock as it was.
+        let mut restocks = Vec::with_capacity(self.products.len());
+        for (key, product_data) in &self.products {
+            let stock = if delta {
+                product_data
+                    .quantity
+                    .checked_add(quantity)
+                    .ok_or(MarketError::QuantityExceeded)?
+            } else {
+                quantity
+            };
+            let total_minted = if stock > product_data.quantity {
+                minted(product_data, stock - product_data.quantity)?
+            } else {
+                product_data.total_minted
+            };
+            restocks.push((key.clone(), stock, total_minted));
+        }
+
+        let product_count = restocks.len() as u32;
+        for (key, stock, total_minted) in restocks {
+            let Some(product_data) = self.product_mut(&key) else {
+                continue;
+            };
+            let previous_stock = product_data.quantity;
+            product_data.quantity = stock;
+            product_data.total_minted = total_minted;
+            self.record_stock_change(&key, stock_delta(previous_stock, stock));
+            self.stock_added(&key, previous_stock);
+            let stock = self
+                .products
+                .get(&key)
+                .map_or(0, |product_data| product_data.quantity);
+            self.notify_inventory_oracle(key, stock);
+        }
+
+        self.snapshot_inventory();
+
+        Ok(MarketEvent::AllRestocked { product_count })
+    }
+    fn set_product_category(
+        &mut self,
+        name: String,
+        category: Option<String>,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SetProductCategory")?;
+        if category
+            .as_ref()
+            .is_some_and(|category| category.is_empty() || category.len() > MAX_CATEGORY_LEN)
+        {
+            return Err(MarketError::InvalidCategory);
+        }
+        let key = self.product_key(&name);
+        let product_data = self
+            .product_mut(&key)
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        product_data.category = category.clone();
+
+        Ok(MarketEvent::ProductCategorySet { name, category })
+    }
+    fn rename_category(&mut self, from: String, into: String) -> Result<MarketEvent, MarketError> {
+        self.check_admin("RenameCategory")?;
+        if [&from, &into]
+            .iter()
+            .any(|category| category.is_empty() || category.len() > MAX_CATEGORY_LEN)
+        {
+            return Err(MarketError::InvalidCategory);
+        }
+
+        let block = exec::block_height().into();
+        let mut updated_count = 0;
+        for product_data in self.products.values_mut() {
+            if product_data.category.as_ref() == Some(&from) {
+                product_data.category = Some(into.clone());
+                product_data.last_modified = block;
+                updated_count += 1;
+            }
+        }
+
+        Ok(MarketEvent::CategoryRenamed {
+            from,
+            into,
+            updated_count,
+        })
+    }
+    fn freeze_product(&mut self, name: String, frozen: bool) -> Result<MarketEvent, MarketError> {
+        self.check_admin("FreezeProduct")?;
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        product_data.frozen = frozen;
+
+        Ok(MarketEvent::ProductFreezeUpdated { name, frozen })
+    }
+    fn start_flash_sale(
+        &mut self,
+        name: String,
+        sale_price: u128,
+        duration_blocks: u32,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("StartFlashSale")?;
+        if sale_price < exec::env_vars().existential_deposit {
+            return Err(MarketError::PriceLessThanExistentialDeposit);
+        }
+        if duration_blocks == 0 {
+            return Err(MarketError::InvalidFlashSale);
+        }
+        let key = self.product_key(&name);
+        let product_data = self
+            .products
+            .get(&key)
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+        let current_block: u64 = exec::block_height().into();
+        if product_data
+            .flash_sale
+            .as_ref()
+            .is_some_and(|flash_sale| flash_sale.ends_at > current_block)
+        {
+            return Err(MarketError::FlashSaleActive);
+        }
+        if sale_price >= product_data.price {
+            return Err(MarketError::InvalidFlashSale);
+        }
+
+        let ends_at = current_block + u64::from(duration_blocks);
+        let reservation = exec::reserve_gas(FLASH_SALE_END_GAS, duration_blocks.saturating_add(1))
+            .expect("Unable to reserve gas for ending the flash sale");
+        msg::send_delayed_from_reservation(
+            reservation,
+            exec::program_id(),
+            MarketAction::EndFlashSale { name: key.clone() },
+            0,
+            duration_blocks,
+        )
+        .expect("Error in scheduling the end of the flash sale");
+        if let Some(product_data) = self.product_mut(&key) {
+            product_data.flash_sale = Some(FlashSale {
+                sale_price,
+                ends_at,
+            });
+        }
+
+        Ok(MarketEvent::FlashSaleStarted {
+            name,
+            sale_price,
+            ends_at,
+        })
+    }
+    fn end_flash_sale(&mut self, name: String) -> Result<MarketEvent, MarketError> {
+        let scheduled = msg::source() == exec::program_id();
+        if !scheduled {
+            self.check_admin("EndFlashSale")?;
+        }
+        let key = self.product_key(&name);
+        let ends_at = self
+            .products
+            .get(&key)
+            .and_then(|product_data| product_data.flash_sale.as_ref())
+            .map(|flash_sale| flash_sale.ends_at)
+            .ok_or(MarketError::NoFlashSale)?;
+        // The scheduled end of a sale that was since replaced by a later one.
+        if scheduled && ends_at > exec::block_height().into() {
+            return Err(MarketError::NoFlashSale);
+        }
+        if let Some(product_data) = self.product_mut(&key) {
+            product_data.flash_sale = None;
+        }
+
+        Ok(MarketEvent::FlashSaleEnded { name })
+    }
+    fn set_product_translation(
+        &mut self,
+        name: String,
+        lang: String,
+        translation: String,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SetProductTranslation")?;
+        if lang.is_empty() || lang.len() > MAX_LANG_LEN {
+            return Err(MarketError::InvalidTranslation);
+        }
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        if translation.is_empty() {
+            product_data.translations.remove(&lang);
+        } else {
+            product_data
+                .translations
+                .insert(lang.clone(), translation.clone());
+        }
+
+        Ok(MarketEvent::ProductTranslationSet {
+            name,
+            lang,
+            translation,
+        })
+    }
+    fn set_allowed_countries(
+        &mut self,
+        name: String,
+        countries: Option<Vec<String>>,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SetAllowedCountries")?;
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        product_data.allowed_countries =
+            countries.clone().filter(|countries| !countries.is_empty());
+
+        Ok(MarketEvent::AllowedCountriesSet { name, countries })
+    }
+    fn set_product_attribute(
+        &mut self,
+        name: String,
+        key: String,
+        value: String,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SetProductAttribute")?;
+        let max_len = self.config.max_attribute_len as usize;
+        if key.is_empty() || key.len() > max_len || value.len() > max_len {
+            return Err(MarketError::InvalidAttribute);
+        }
+        let max_attributes = self.config.max_attributes as usize;
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        let attributes = &mut product_data.attributes;
+        if let Some((_, attribute_value)) = attributes
+            .iter_mut()
+            .find(|(attribute_key, _)| *attribute_key == key)
+        {
+            *attribute_value = value.clone();
+        } else if attributes.len() >= max_attributes {
+            return Err(MarketError::TooManyAttributes);
+        } else {
+            attributes.push((key.clone(), value.clone()));
+        }
+
+        Ok(MarketEvent::ProductAttributeSet { name, key, value })
+    }
+    fn remove_product_attribute(
+        &mut self,
+        name: String,
+        key: String,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("RemoveProductAttribute")?;
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        let position = product_data
+            .attributes
+            .iter()
+            .position(|(attribute_key, _)| *attribute_key == key)
+            .ok_or(MarketError::ThereIsNoSuchAttribute)?;
+        product_data.attributes.remove(position);
+
+        Ok(MarketEvent::ProductAttributeRemoved { name, key })
+    }
+    fn set_product_expiry(
+        &mut self,
+        name: String,
+        expires_at: Option<u64>,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SetProductExpiry")?;
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        product_data.expires_at = expires_at;
+
+        Ok(MarketEvent::ProductExpirySet { name, expires_at })
+    }
+    fn set_purchase_cooldown(
+        &mut self,
+        name: String,
+        cooldown_blocks: Option<u64>,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SetPurchaseCooldown")?;
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        product_data.cooldown_blocks = cooldown_blocks;
+
+        Ok(MarketEvent::PurchaseCooldownSet {
+            name,
+            cooldown_blocks,
+        })
+    }
+    fn set_featured(&mut self, names: Vec<String>) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SetFeatured")?;
+        if names.len() > MAX_FEATURED {
+            return Err(MarketError::TooManyFeatured);
+        }
+        let mut featured: Vec<String> = Vec::with_capacity(names.len());
+        for name in names {
+            let key = self.product_key(&name);
+            if !self.products.contains_key(&key) {
+                return Err(MarketError::ThereIsNoSuchName);
+            }
+            if featured.contains(&key) {
+                return Err(MarketError::AlreadyExists);
+            }
+            featured.push(key);
+        }
+        self.featured = featured.clone();
+
+        Ok(MarketEvent::FeaturedSet(featured))
+    }
+    fn add_featured(&mut self, name: String) -> Result<MarketEvent, MarketError> {
+        self.check_admin("AddFeatured")?;
+        let key = self.product_key(&name);
+        if !self.products.contains_key(&key) {
+            return Err(MarketError::ThereIsNoSuchName);
+        }
+        if self.featured.contains(&key) {
+            return Err(MarketError::AlreadyExists);
+        }
+        if self.featured.len() >= MAX_FEATURED {
+            return Err(MarketError::TooManyFeatured);
+        }
+        self.featured.push(key);
+
+        Ok(MarketEvent::FeaturedSet(self.featured.clone()))
+    }
+    fn remove_featured(&mut self, name: String) -> Result<MarketEvent, MarketError> {
+        self.check_admin("RemoveFeatured")?;
+        let key = self.product_key(&name);
+        let index = self
+            .featured
+            .iter()
+            .position(|featured| *featured == key)
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+        self.featured.remove(index);
+
+        Ok(MarketEvent::FeaturedSet(self.featured.clone()))
+    }
+    fn add_allowlisted_product(
+        &mut self,
+        name: String,
+        quantity: u128,
+        price: u128,
+        allowlist: Vec<ActorId>,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("AddAllowlistedProduct")?;
+        self.insert_product(
+            name.clone(),
+            quantity,
+            price,
+            None,
+            false,
+            String::new(),
+            false,
+            None,
+        )?;
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .expect("The product was just added");
+        product_data.allowlist = Some(allowlist.clone()).filter(|allowlist| !allowlist.is_empty());
+
+        Ok(MarketEvent::AllowlistedProductAdded {
+            name,
+            quantity,
+            price,
+            allowlist,
+        })
+    }
+    fn lift_allowlist(&mut self, name: String) -> Result<MarketEvent, MarketError> {
+        self.check_admin("LiftAllowlist")?;
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        product_data.allowlist = None;
+
+        Ok(MarketEvent::AllowlistLifted { name })
+    }
+    fn set_refund_percent(
+        &mut self,
+        name: String,
+        refund_percent: u16,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SetRefundPercent")?;
+        if refund_percent > FULL_REFUND_PERCENT {
+            return Err(MarketError::InvalidRefundPercent);
+        }
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        product_data.refund_percent = refund_percent;
+
+        Ok(MarketEvent::RefundPercentSet {
+            name,
+            refund_percent,
+        })
+    }
+    fn set_price_tiers(
+        &mut self,
+        name: String,
+        mut tiers: Vec<PriceTier>,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SetPriceTiers")?;
+        if tiers.len() > MAX_PRICE_TIERS {
+            return Err(MarketError::TooManyPriceTiers);
+        }
+        let existential_deposit = exec::env_vars().existential_deposit;
+        for tier in &tiers {
+            if tier.min_quantity == 0 {
+                return Err(MarketError::ZeroQuantity);
+            }
+            if tier.unit_price < existential_deposit {
+                return Err(MarketError::PriceLessThanExistentialDeposit);
+            }
+        }
+        tiers.sort_unstable_by_key(|tier| tier.min_quantity);
+        if tiers
+            .windows(2)
+            .any(|pair| pair[0].min_quantity == pair[1].min_quantity)
+        {
+            return Err(MarketError::DuplicateTierThreshold);
+        }
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        product_data.price_tiers = tiers.clone();
+
+        Ok(MarketEvent::PriceTiersSet { name, tiers })
+    }
+    fn set_product_cost(
+        &mut self,
+        name: String,
+        cost: Option<u128>,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SetProductCost")?;
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        product_data.cost = cost;
+
+        Ok(MarketEvent::ProductCostSet { name, cost })
+    }
+    fn set_unit_scale(
+        &mut self,
+        name: String,
+        unit_scale: u128,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SetUnitScale")?;
+        let product_data = self
+            .product_mut(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+        // Past the price, a single stock unit would cost less than one unit of value.
+        if unit_scale == 0 || unit_scale > product_data.price {
+            return Err(MarketError::InvalidUnitScale);
+        }
+
+        product_data.unit_scale = unit_scale;
+
+        Ok(MarketEvent::UnitScaleSet { name, unit_scale })
+    }
+    fn add_bundle(
+        &mut self,
+        name: String,
+        components: Vec<(String, u128)>,
+        price: u128,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("AddBundle")?;
+        let key = self.product_key(&name);
+        if self.products.contains_key(&key) || self.bundles.contains_key(&key) {
+            return Err(MarketError::AlreadyExists);
+        }
+        if price < exec::env_vars().existential_deposit {
+            return Err(MarketError::PriceLessThanExistentialDeposit);
+        }
+        if components.is_empty() {
+            return Err(MarketError::ZeroQuantity);
+        }
+
+        // Listing the same component twice would let each line pass the stock check on its own.
+        let mut merged: Vec<(String, u128)> = Vec::with_capacity(components.len());
+        for (product_name, per_bundle) in &components {
+            if *per_bundle == 0 {
+                return Err(MarketError::ZeroQuantity);
+            }
+            let product_key = self.product_key(product_name);
+            if !self.products.contains_key(&product_key) {
+                return Err(MarketError::ThereIsNoSuchName);
+            }
+            match merged
+                .iter_mut()
+                .find(|(merged_key, _)| *merged_key == product_key)
+            {
+                Some((_, total)) => {
+                    *total = total
+                        .checked_add(*per_bundle)
+                        .ok_or(MarketError::QuantityExceeded)?
+                }
+                None => merged.push((product_key, *per_bundle)),
+            }
+        }
+
+        self.bundles.insert(
+            key,
+            BundleData {
+                components: merged,
+                price,
+            },
+        );
+
+        Ok(MarketEvent::BundleAdded {
+            name,
+            components,
+            price,
+        })
+    }
+    fn schedule_price_change(
+        &mut self,
+        name: String,
+        new_price: u128,
+        effective_block: u64,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SchedulePriceChange")?;
+        if new_price < exec::env_vars().existential_deposit {
+            return Err(MarketError::PriceLessThanExistentialDeposit);
+        }
+        let current_block: u64 = exec::block_height().into();
+        if effective_block <= current_block {
+            return Err(MarketError::EffectiveBlockPassed);
+        }
+        let key = self.product_key(&name);
+        if !self.products.contains_key(&key) {
+            return Err(MarketError::ThereIsNoSuchName);
+        }
+
+        self.price_changes.insert((effective_block, key), new_price);
+
+        Ok(MarketEvent::PriceChangeScheduled {
+            name,
+            new_price,
+            effective_block,
+        })
+    }
+    /// Applies the scheduled price changes that have taken effect, oldest first. Run before every
+    /// message and state query, so a change counts from its block on without anyone sending it.
+    fn apply_price_changes(&mut self) {
+        let current_block: u64 = exec::block_height().into();
+        let existential_deposit = exec::env_vars().existential_deposit;
+        while let Some(entry) = self.price_changes.first_entry() {
+            if entry.key().0 > current_block {
+                break;
+            }
+            let ((_, key), new_price) = entry.remove_entry();
+            if new_price < existential_deposit {
+                continue;
+            }
+            if let Some(product_data) = self.product_mut(&key) {
+                product_data.price = new_price;
+            }
+        }
+    }
+    fn update_product_info(
+        &mut self,
+        name: String,
+        quantity: Option<u128>,
+        price: Option<u128>,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("UpdateProductInfo")?;
+        self.set_product_info(name, quantity, price)
+    }
+    /// Sets the stock and price of product `name` for `update_product_info` and
+    /// `compare_and_update_product`, leaving the admin check to them.
+    fn set_product_info(
+        &mut self,
+        name: String,
+        quantity: Option<u128>,
+        price: Option<u128>,
+    ) -> Result<MarketEvent, MarketError> {
+        let key = self.product_key(&name);
+        let product_data = self
+            .product_mut(&key)
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+
+        let previous_quantity = product_data.quantity;
+        if let Some(quantity) = quantity {
+            if quantity > product_data.quantity {
+                mint(product_data, quantity - product_data.quantity)?;
+            }
+            product_data.quantity = quantity;
+        }
+        if let Some(price) = price {
+            product_data.price = price;
+        }
+        if let Some(quantity) = quantity {
+            self.record_stock_change(&key, stock_delta(previous_quantity, quantity));
+            self.stock_added(&key, previous_quantity);
+        }
+
+        Ok(MarketEvent::ProductInfoUpdated {
+            name,
+            quantity,
+            price,
+        })
+    }
+    fn compare_and_update_product(
+        &mut self,
+        name: String,
+        expected_quantity: u128,
+        expected_price: u128,
+        new_quantity: u128,
+        new_price: u128,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("CompareAndUpdateProduct")?;
+        let product_data = self
+            .products
+            .get(&self.product_key(&name))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+        if product_data.quantity != expected_quantity || product_data.price != expected_price {
+            return Err(MarketError::ConcurrentModification);
+        }
+        self.set_product_info(name, Some(new_quantity), Some(new_price))
+    }
+    fn update_config(&mut self, config: Config) -> Result<MarketEvent, MarketError> {
+        self.check_admin("UpdateConfig")?;
+        validate_config(&config)?;
+        self.config = config.clone();
+        Ok(MarketEvent::ConfigUpdated { config })
+    }
+    fn delete_product(&mut self, name: String) -> Result<MarketEvent, MarketError> {
+        self.check_admin("DeleteProduct")?;
+
+        if !self.remove_product(&name) {
+            return Err(MarketError::ThereIsNoSuchName);
+        }
+
+        Ok(MarketEvent::ProductDeleted { name })
+    }
+    fn delete_products_batch(&mut self, names: Vec<String>) -> Result<MarketEvent, MarketError> {
+        self.check_admin("DeleteProductsBatch")?;
+        self.check_batch_size(names.len())?;
+
+        let (deleted, not_found) = names
+            .into_iter()
+            .partition(|name| self.remove_product(name));
+
+        Ok(MarketEvent::ProductsBatchDeleted { deleted, not_found })
+    }
+    fn merge_products(&mut self, from: String, into: String) -> Result<MarketEvent, MarketError> {
+        self.check_admin("MergeProducts")?;
+        let (from_key, into_key) = (self.product_key(&from), self.product_key(&into));
+        if from_key == into_key {
+            return Err(MarketError::SameProduct);
+        }
+        let (quantity, units_sold) = self
+            .products
+            .get(&from_key)
+            .map(|product_data| (product_data.quantity, product_data.units_sold))
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+        let product_data = self
+            .products
+            .get(&into_key)
+            .ok_or(MarketError::ThereIsNoSuchName)?;
+        let total_minted = minted(product_data, quantity)?;
+        let previous_stock = product_data.quantity;
+        let stock = product_data
+            .quantity
+            .checked_add(quantity)
+            .ok_or(MarketError::QuantityExceeded)?;
+
+        // Reserved units were taken out of `from` and so come back as stock of `into`.
+        for reservation in self.reservations.values_mut() {
+            if reservation.name == from_key {
+                reservation.name = into_key.clone();
+            }
+        }
+        if let Some(product_data) = self.product_mut(&into_key) {
+            product_data.total_minted = total_minted;
+            product_data.quantity = stock;
+            product_data.units_sold = product_data.units_sold.saturating_add(units_sold);
+        }
+        for purchase in self.purchases.values_mut().flatten() {
+            if purchase.name == from_key {
+                purchase.name = into_key.clone();
+            }
+        }
+        for bundle in self.bundles.values_mut() {
+            if let Some(index) = bundle
+                .components
+                .iter()
+                .position(|(component, _)| *component == from_key)
+            {
+                let (_, units) = bundle.components.remove(index);
+                match bundle
+                    .components
+                    .iter_mut()
+                    .find(|(component, _)| *component == into_key)
+                {
+                    Some((_, into_units)) => *into_units = into_units.saturating_add(units),
+                    None => bundle.components.insert(index, (into_key.clone(), units)),
+                }
+            }
+        }
+        // Orders waiting for `from` keep their place behind those already waiting for `into`.
+        if let Some(waiting) = self.preorders.remove(&from_key) {
+            self.preorders
+                .entry(into_key.clone())
+                .or_default()
+                .extend(waiting);
+        }
+        self.remove_product(&from_key);
+        if quantity != 0 {
+            self.record_stock_change(&into_key, stock_delta(0, quantity));
+        }
+        self.stock_added(&into_key, previous_stock);
+        let stock = self
+            .products
+            .get(&into_key)
+            .map_or(0, |product_data| product_data.quantity);
+        self.notify_inventory_oracle(into_key, stock);
+        self.snapshot_inventory();
+
+        Ok(MarketEvent::ProductsMerged {
+            from,
+            into,
+            quantity,
+        })
+    }
+    fn revalidate_prices(&mut self) -> Result<MarketEvent, MarketError> {
+        self.check_admin("RevalidatePrices")?;
+        let existential_deposit = exec::env_vars().existential_deposit;
+
+        let products = self
+            .products
+            .iter()
+            .filter(|(_, product_data)| {
+                product_data.price.min(current_price(product_data)) < existential_deposit
+                    || product_data
+                        .price_tiers
+                        .iter()
+                        .any(|tier| tier.unit_price < existential_deposit)
+            })
+            .map(|(key, _)| key);
+        let bundles = self
+            .bundles
+            .iter()
+            .filter(|(_, bundle)| bundle.price < existential_deposit)
+            .map(|(key, _)| key);
+        let mut names: Vec<String> = products.chain(bundles).cloned().collect();
+        names.sort_unstable();
+
+        Ok(MarketEvent::PricesNeedingAttention(names))
+    }
+    /// Removes the product or bundle called `name`, returning whether there was one.
+    fn remove_product(&mut self, name: &str) -> bool {
+        let key = self.product_key(name);
+        self.refund_reservations(&key);
+        self.refund_preorders(&key);
+        if let Some(product_data) = self.products.remove(&key) {
+            self.sku_index.remove(&product_data.sku);
+            self.deleted_products
+                .insert(key.clone(), exec::block_height().into());
+        } else if self.bundles.remove(&key).is_none() {
+            return false;
+        }
+        self.stock_history.remove(&key);
+        self.restock_subscribers.remove(&key);
+        self.featured.retain(|featured| *featured != key);
+        self.price_changes.retain(|(_, name), _| *name != key);
+        true
+    }
+    /// Checks that `quantity` units of the product or bundle stored under `key` can be bought and
+    /// prices them, without touching any state.
+    fn quote(&self, key: &str, quantity: u128) -> Result<Quote, MarketError> {
+        let (unit_price, stock_lines) = self.stock_lines(key, quantity)?;
+        if quantity == 0 {
+            return Err(MarketError::ZeroQuantity);
+        }
+        let is_bundle = self.bundles.contains_key(key);
+        for (product_name, needed) in &stock_lines {
+            let Some(product_data) = self.products.get(product_name) else {
+                return Err(MarketError::BundleComponentUnavailable);
+            };
+            if product_data.frozen {
+                return Err(MarketError::ProductFrozen);
+            }
+            if expired(product_data) {
+                return Err(MarketError::ProductExpired);
+            }
+            let current_block: u64 = exec::block_height().into();
+            if current_block
+                < product_data
+                    .listed_at
+                    .saturating_add(self.config.listing_delay_blocks)
+            {
+                return Err(MarketError::ProductNotYetBuyable);
+            }
+            // Bundles are only sold from stock, even when a component takes preorders.
+            let preorder = product_data.preorder && !is_bundle;
+            if *needed > product_data.quantity && !preorder {
+                if is_bundle {
+                    return Err(MarketError::BundleComponentUnavailable);
+                }
+                return Err(MarketError::QuantityExceeded);
+            }
+        }
+
+        // Bundles are always sold whole.
+        let unit_scale = self
+            .products
+            .get(key)
+            .map_or(1, |product_data| product_data.unit_scale);
+        let total = self
+            .config
+            .rounding
+            .mul_div(unit_price, quantity, unit_scale)
+            .ok_or(MarketError::QuantityExceeded)?;
+        // A few units of a finely scaled product can come to less than can be paid, or to nothing.
+        if total == 0 || total < exec::env_vars().existential_deposit {
+            return Err(MarketError::PriceLessThanExistentialDeposit);
+        }
+
+        Ok(Quote { unit_price, total })
+    }
+    /// Runs the checks of `quote` and works out the stock the purchase would leave.
+    fn simulate_buy(&self, key: &str, quantity: u128) -> Result<BuySimulation, MarketError> {
+        let quote = self.quote(key, quantity)?;
+        let (_, stock_lines) = self.stock_lines(key, quantity)?;
+        let quantities_left = stock_lines
+            .into_iter()
+            .map(|(product_name, needed)| {
+                let quantity = self
+                    .products
+                    .get(&product_name)
+                    .map_or(0, |product_data| product_data.quantity);
+                (product_name, quantity.saturating_sub(needed))
+            })
+            .collect();
+
+        Ok(BuySimulation {
+            total_payment: quote.total,
+            quantities_left,
+        })
+    }
+    /// Runs every check of a purchase and works out how it's paid for, without touching any
+    /// state. `buy` only applies the plan.
+    #[allow(clippy::too_many_arguments)]
+    fn validate_buy(
+        &self,
+        msg_source: ActorId,
+        msg_value: u128,
+        name: &str,
+        quantity: u128,
+        delivery_address: &DeliveryAddress,
+        contact: Option<&str>,
+        max_unit_price: Option<u128>,
+        exact: bool,
+        use_credit: bool,
+        redeem_points: u128,
+    ) -> Result<BuyPlan, MarketError> {
+        self.check_not_blocked(msg_source)?;
+        self.check_open_orders(msg_source, 1)?;
+        if contact.is_some_and(|contact| {
+            contact.is_empty() || contact.len() > self.config.max_contact_len as usize
+        }) {
+            return Err(MarketError::InvalidContact);
+        }
+        let key = self.product_key(name);
+        let (
+            Quote {
+                unit_price,
+                total: total_payment,
+            },
+            stock_lines,
+            cooldowns,
+        ) = self.check_line(msg_source, &key, quantity, delivery_address)?;
+        if max_unit_price.is_some_and(|max_unit_price| unit_price > max_unit_price) {
+            return Err(MarketError::PriceAboveMax);
+        }
+        let credit = if use_credit {
+            self.credits.get(&msg_source).copied().unwrap_or_default()
+        } else {
+            0
+        };
+        let credit_used = credit.min(total_payment);
+        let points = self
+            .loyalty_points
+            .get(&msg_source)
+            .copied()
+            .unwrap_or_default();
+        if redeem_points > points {
+            return Err(MarketError::InsufficientPoints);
+        }
+        let points_redeemed = redeem_points.min(total_payment - credit_used);
+        // What's left for the attached value to cover.
+        let value_due = total_payment - credit_used - points_redeemed;
+        if exact && msg_value != value_due {
+            return Err(MarketError::InexactPayment);
+        }
+        if msg_value < value_due {
+            return Err(MarketError::InsufficientValue);
+        }
+
+        let spent = self.check_caps(msg_source, value_due, msg_value)?;
+
+        // Preorders are served first come, first served, so a new order joins the queue whenever
+        // there is one, even if the stock could cover it.
+        let preordered = self.products.get(&key).is_some_and(|product_data| {
+            product_data.preorder
+                && (quantity > product_data.quantity || self.preorders.contains_key(&key))
+        });
+        // `quote` has already checked the stock; this keeps any path that gets around that check
+        // from wrapping the stock around instead of failing.
+        let in_stock = preordered
+            || stock_lines.iter().all(|(product_name, needed)| {
+                self.products
+                    .get(product_name)
+                    .map_or(true, |product_data| *needed <= product_data.quantity)
+            });
+        debug_assert!(in_stock, "Buying more than is in stock");
+        if !in_stock {
+            return Err(MarketError::InventoryInvariantViolated);
+        }
+        let points_earned = value_due
+            .checked_div(self.config.points_divisor)
+            .unwrap_or_default();
+
+        Ok(BuyPlan {
+            key,
+            unit_price,
+            stock_lines,
+            cooldowns,
+            preordered,
+            value_due,
+            change: msg_value - value_due,
+            spent,
+            credit_balance: credit - credit_used,
+            credit_used,
+            points_balance: points - points_redeemed + points_earned,
+            points_redeemed,
+            points_earned,
+        })
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn buy(
+        &mut self,
+        msg_source: ActorId,
+        msg_value: u128,
+        name: String,
+        quantity: u128,
+        delivery_address: DeliveryAddress,
+        contact: Option<String>,
+        max_unit_price: Option<u128>,
+        exact: bool,
+        use_credit: bool,
+        redeem_points: u128,
+    ) -> Result<MarketEvent, MarketError> {
+        let BuyPlan {
+            key,
+            unit_price,
+            stock_lines,
+            cooldowns,
+            preordered,
+            value_due,
+            change,
+            spent,
+            credit_balance,
+            credit_used,
+            points_balance,
+            points_redeemed,
+            points_earned,
+        } = self.validate_buy(
+            msg_source,
+            msg_value,
+            &name,
+            quantity,
+            &delivery_address,
+            contact.as_deref(),
+            max_unit_price,
+            exact,
+            use_credit,
+            redeem_points,
+        )?;
+        let current_block: u64 = exec::block_height().into();
+        let auto_delist = self.config.auto_delist_on_zero;
+        let mut delisted = Vec::new();
+        for (product_name, needed) in stock_lines {
+            if let Some(product_data) = self.product_mut(&product_name) {
+                product_data.units_sold = product_data.units_sold.saturating_add(needed);
+                if !preordered {
+                    product_data.quantity -= needed;
+                    if auto_delist && product_data.quantity == 0 && !product_data.preorder {
+                        product_data.active = false;
+                        delisted.push(product_name.clone());
+                    }
+                    self.record_stock_change(&product_name, stock_delta(needed, 0));
+                }
+            }
+        }
+        for name in delisted {
+            let notification = self.sequence(MarketEvent::ProductAutoDelisted { name });
+            msg::send(self.admin, notification, 0).expect("Error in sending a notification");
+        }
+        let tip = if self.config.refund_overpayment {
+            self.send_value(msg_source, change);
+            0
+        } else {
+            change
+        };
+        *self.pending_payouts.entry(self.admin).or_default() += value_due + tip;
+        self.total_spent.insert(msg_source, spent);
+
+        let order_id = self.new_order_id(msg_source);
+        if credit_used != 0 {
+            if credit_balance == 0 {
+                self.credits.remove(&msg_source);
+            } else {
+                self.credits.insert(msg_source, credit_balance);
+            }
+            let notification = self.sequence(MarketEvent::CreditUsed {
+                order_id,
+                amount: credit_used,
+                balance: credit_balance,
+            });
+            msg::send(msg_source, notification, 0).expect("Error in sending a notification");
+        }
+        if points_balance == 0 {
+            self.loyalty_points.remove(&msg_source);
+        } else {
+            self.loyalty_points.insert(msg_source, points_balance);
+        }
+        // Instant products never take preorders, so their orders are always filled from stock.
+        let delivery = self
+            .products
+            .get(&key)
+            .filter(|product_data| product_data.instant)
+            .map(|product_data| product_data.delivery_payload.clone());
+        if let Some(delivery_payload) = delivery.clone() {
+            let notification = self.sequence(MarketEvent::InstantlyDelivered {
+                order_id,
+                delivery_payload,
+            });
+            msg::send(msg_source, notification, 0).expect("Error in sending a notification");
+        }
+        if preordered {
+            self.preorders
+                .entry(key.clone())
+                .or_default()
+                .push_back(order_id);
+        }
+
+        let block = exec::block_height().into();
+        let timestamp = exec::block_timestamp();
+        let status = if preordered {
+            Status::Preordered
+        } else if delivery.is_some() {
+            Status::Delivered
+        } else {
+            Status::PaidFor
+        };
+        let new_purchase = PurchaseData {
+            order_id,
+            name: key.clone(),
+            quantity,
+            status_history: vec![(block, status.clone())],
+            status,
+            delivery_address,
+            contact,
+            block,
+            timestamp,
+            unit_price,
+            total_paid: value_due,
+            credit_used,
+            points_redeemed,
+            points_earned,
+            tracking_number: None,
+        };
+        self.purchases
+            .entry(msg_source)
+            .and_modify(|purchase| purchase.push(new_purchase.clone()))
+            .or_insert(vec![new_purchase]);
+        self.order_index.insert(order_id, msg_source);
+        self.trim_history(msg_source);
+        for (product_name, _) in cooldowns {
+            self.last_buy
+                .insert((msg_source, product_name), current_block);
+        }
+        self.snapshot_inventory();
+
+        if self.config.buy_receipts {
+            let mut receipt = Receipt {
+                order_id,
+                buyer: msg_source,
+                name: key,
+                unit_price,
+                quantity,
+                total_paid: value_due,
+                change: change - tip,
+                tip,
+                block,
+                timestamp,
+                hash: [0; 32],
+            };
+            receipt.hash = receipt_hash(&receipt);
+            return Ok(MarketEvent::Receipt(receipt));
+        }
+
+        Ok(MarketEvent::Bought {
+            buyer: msg_source,
+            name,
+            quantity,
+            order_id,
+            tip,
+        })
+    }
+    /// Checks one product or bundle of a purchase, returning its quote, the stock it takes from
+    /// each product and the purchase cooldowns of those products.
+    #[allow(clippy::type_complexity)]
+    fn check_line(
+        &self,
+        msg_source: ActorId,
+        key: &str,
+        quantity: u128,
+        delivery_address: &DeliveryAddress,
+    ) -> Result<(Quote, Vec<(String, u128)>, Vec<(String, u64)>), MarketError> {
+        let quote = self.quote(key, quantity)?;
+        let (_, stock_lines) = self.stock_lines(key, quantity)?;
+        let deliverable = stock_lines.iter().all(|(product_name, _)| {
+            self.products
+                .get(product_name)
+                .and_then(|product_data| product_data.allowed_countries.as_ref())
+                .map_or(true, |countries| {
+                    countries
+                        .iter()
+                        .any(|country| country.eq_ignore_ascii_case(&delivery_address.country))
+                })
+        });
+        if !deliverable {
+            return Err(MarketError::NotDeliverableToRegion);
+        }
+        let allowed = stock_lines.iter().all(|(product_name, _)| {
+            self.products
+                .get(product_name)
+                .and_then(|product_data| product_data.allowlist.as_ref())
+                .map_or(true, |allowlist| allowlist.contains(&msg_source))
+        });
+        if !allowed {
+            return Err(MarketError::NotOnAllowlist);
+        }
+        let current_block: u64 = exec::block_height().into();
+        let cooldowns: Vec<(String, u64)> = stock_lines
+            .iter()
+            .filter_map(|(product_name, _)| {
+                let cooldown_blocks = self.products.get(product_name)?.cooldown_blocks?;
+                Some((product_name.clone(), cooldown_blocks))
+            })
+            .collect();
+        for (product_name, cooldown_blocks) in &cooldowns {
+            let cooling_down = self
+                .last_buy
+                .get(&(msg_source, product_name.clone()))
+                .is_some_and(|last_buy| current_block < last_buy.saturating_add(*cooldown_blocks));
+            if cooling_down {
+                return Err(MarketError::PurchaseCooldownActive);
+            }
+        }
+        Ok((quote, stock_lines, cooldowns))
+    }
+    /// Checks that paying `value_due` out of `msg_value` keeps the buyer within
+    /// `Config::max_spend_per_actor` and the market within `Config::max_contract_value`,
+    /// returning what the buyer will have spent.
+    fn check_caps(
+        &self,
+        msg_source: ActorId,
+        value_due: u128,
+        msg_value: u128,
+    ) -> Result<u128, MarketError> {
+        let spent = self
+            .total_spent
+            .get(&msg_source)
+            .copied()
+            .unwrap_or_default();
+        let spent = spent
+            .checked_add(value_due)
+            .ok_or(MarketError::SpendCapExceeded)?;
+        if let Some(cap) = self.config.max_spend_per_actor {
+            if spent > cap {
+                return Err(MarketError::SpendCapExceeded);
+            }
+        }
+        if let Some(cap) = self.config.max_contract_value {
+            let kept = if self.config.refund_overpayment {
+                value_due
+            } else {
+                msg_value
+            };
+            if self
+                .held_value()
+                .checked_add(kept)
+                .map_or(true, |held| held > cap)
+            {
+                return Err(MarketError::ContractValueCapReached);
+            }
+        }
+        Ok(spent)
+    }
+    /// Buys every line of the cart or none. The lines are checked in order against the stock
+    /// the earlier ones leave, and are then bought one by one with exactly their total attached,
+    /// the last one also carrying any overpayment.
+    fn buy_cart(
+        &mut self,
+        msg_source: ActorId,
+        msg_value: u128,
+        items: Vec<(String, u128)>,
+        delivery_address: DeliveryAddress,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_not_blocked(msg_source)?;
+        self.check_batch_size(items.len())?;
+        if items.is_empty() {
+            return Err(MarketError::ZeroQuantity);
+        }
+        self.check_open_orders(msg_source, items.len())?;
+
+        let mut taken: BTreeMap<String, u128> = BTreeMap::new();
+        let mut cooling_down: BTreeSet<String> = BTreeSet::new();
+        let mut totals = Vec::with_capacity(items.len());
+        let mut cart_total = 0u128;
+        for (name, quantity) in &items {
+            let unavailable = || MarketError::CartItemUnavailable { name: name.clone() };
+            let key = self.product_key(name);
+            let (quote, stock_lines, cooldowns) = self
+                .check_line(msg_source, &key, *quantity, &delivery_address)
+                .map_err(|error| match error {
+                    MarketError::QuantityExceeded | MarketError::BundleComponentUnavailable => {
+                        unavailable()
+                    }
+                    error => error,
+                })?;
+            for (product_name, needed) in stock_lines {
+                let stock = self
+                    .products
+                    .get(&product_name)
+                    .map_or(0, |product_data| product_data.quantity);
+                let taken = taken.entry(product_name).or_default();
+                *taken = taken.checked_add(needed).ok_or_else(unavailable)?;
+                if *taken > stock {
+                    return Err(unavailable());
+                }
+            }
+            // The first line sets off the cooldown for the later ones.
+            for (product_name, _) in cooldowns {
+                if !cooling_down.insert(product_name) {
+                    return Err(MarketError::PurchaseCooldownActive);
+                }
+            }
+            cart_total = cart_total
+                .checked_add(quote.total)
+                .ok_or_else(unavailable)?;
+            totals.push(quote.total);
+        }
+        if msg_value < cart_total {
+            return Err(MarketError::InsufficientValue);
+        }
+        self.check_caps(msg_source, cart_total, msg_value)?;
+
+        let last = items.len() - 1;
+        let mut order_ids = Vec::with_capacity(items.len());
+        for (index, ((name, quantity), total)) in items.into_iter().zip(totals).enumerate() {
+            let value = if index == last {
+                total + (msg_value - cart_total)
+            } else {
+                total
+            };
+            // Failing here would leave the earlier lines bought, so it panics to revert them.
+            let event = self
+                .buy(
+                    msg_source,
+                    value,
+                    name,
+                    quantity,
+                    delivery_address.clone(),
+                    None,
+                    None,
+                    false,
+                    false,
+                    0,
+                )
+                .expect("A checked cart line couldn't be bought");
+            match event {
+                MarketEvent::Bought { order_id, .. } => order_ids.push(order_id),
+                MarketEvent::Receipt(receipt) => order_ids.push(receipt.order_id),
+                _ => unreachable!("`buy` replies with `Bought` or `Receipt`"),
+            }
+        }
+
+        Ok(MarketEvent::CartBought {
+            order_ids,
+            total: cart_total,
+        })
+    }
+    fn reserve_with_deposit(
+        &mut self,
+        msg_source: ActorId,
+        msg_value: u128,
+        name: String,
+        quantity: u128,
+        deposit: u128,
+        delivery_address: DeliveryAddress,
+    ) -> Result<MarketEvent, MarketError> {
+        let reservation_blocks = self
+            .config
+            .reservation_blocks
+            .ok_or(MarketError::ReservationsDisabled)?;
+        self.check_not_blocked(msg_source)?;
+        if self.reservations_of(msg_source) >= MAX_RESERVATIONS_PER_BUYER {
+            return Err(MarketError::TooManyReservations);
+        }
+        self.check_open_orders(msg_source, 1)?;
+        let key = self.product_key(&name);
+        let (quote, stock_lines, _) =
+            self.check_line(msg_source, &key, quantity, &delivery_address)?;
+        // Reservations only hold units that are in stock, even of preorder products.
+        let in_stock = stock_lines.iter().all(|(product_name, needed)| {
+            self.products
+                .get(product_name)
+                .is_some_and(|product_data| *needed <= product_data.quantity)
+        });
+        if !in_stock {
+            return Err(MarketError::QuantityExceeded);
+        }
+        let min_deposit = self
+            .config
+            .rounding
+            .mul_div(
+                quote.total,
+                self.config.min_deposit_percent.into(),
+                FULL_REFUND_PERCENT.into(),
+            )
+            .ok_or(MarketError::DepositTooLow)?;
+        if deposit == 0 || deposit < min_deposit {
+            return Err(MarketError::DepositTooLow);
+        }
+        if msg_value < deposit {
+            return Err(MarketError::InsufficientValue);
+        }
+        self.check_caps(msg_source, deposit, msg_value)?;
+
+        self.move_reserved_stock(&key, quantity, false);
+        self.send_value(msg_source, msg_value - deposit);
+        let reservation_id = self.next_reservation_id;
+        self.next_reservation_id += 1;
+        let gas_reservation = exec::reserve_gas(
+            RESERVATION_FORFEIT_GAS,
+            reservation_blocks.saturating_add(1),
+        )
+        .expect("Unable to reserve gas for forfeiting the reservation");
+        msg::send_delayed_from_reservation(
+            gas_reservation,
+            exec::program_id(),
+            MarketAction::ForfeitReservation { reservation_id },
+            0,
+            reservation_blocks,
+        )
+        .expect("Error in scheduling the forfeiture of the reservation");
+        let current_block: u64 = exec::block_height().into();
+        let reservation = Reservation {
+            buyer: msg_source,
+            name: key,
+            quantity,
+            deposit,
+            expires_at: current_block + u64::from(reservation_blocks),
+            delivery_address,
+            total: quote.total,
+        };
+        self.reservations
+            .insert(reservation_id, reservation.clone());
+
+        Ok(MarketEvent::Reserved {
+            reservation_id,
+            reservation,
+        })
+    }
+    /// Takes the units of a reservation out of stock, or puts them back when `release` is set.
+    fn move_reserved_stock(&mut self, key: &str, quantity: u128, release: bool) {
+        let Ok((_, stock_lines)) = self.stock_lines(key, quantity) else {
+            return;
+        };
+        for (product_name, needed) in stock_lines {
+            if let Some(product_data) = self.product_mut(&product_name) {
+                let (now, delta) = if release {
+                    (
+                        product_data.quantity.saturating_add(needed),
+                        stock_delta(0, needed),
+                    )
+                } else {
+                    (
+                        product_data.quantity.saturating_sub(needed),
+                        stock_delta(needed, 0),
+                    )
+                };
+                product_data.quantity = now;
+                self.record_stock_change(&product_name, delta);
+            }
+        }
+    }
+    /// Puts the units of a reservation that's over back on sale, like any other added stock. The
+    /// product `removed`, if any, is about to be deleted and only gets its units back.
+    fn return_reserved_stock(&mut self, key: &str, quantity: u128, removed: Option<&str>) {
+        for (product_name, previous_stock) in self.release_reserved_stock(key, quantity, removed) {
+            self.stock_added(&product_name, previous_stock);
+        }
+    }
+    /// Puts the units of a reservation back in stock, returning each product other than
+    /// `removed` with the stock it had before, for `stock_added`.
+    fn release_reserved_stock(
+        &mut self,
+        key: &str,
+        quantity: u128,
+        removed: Option<&str>,
+    ) -> Vec<(String, u128)> {
+        let Ok((_, stock_lines)) = self.stock_lines(key, quantity) else {
+            return Vec::new();
+        };
+        let previous: Vec<(String, u128)> = stock_lines
+            .into_iter()
+            .filter(|(product_name, _)| Some(product_name.as_str()) != removed)
+            .filter_map(|(product_name, _)| {
+                let stock = self.products.get(&product_name)?.quantity;
+                Some((product_name, stock))
+            })
+            .collect();
+        self.move_reserved_stock(key, quantity, true);
+        previous
+    }
+    /// Cancels the reservations holding units of the product or bundle stored under `key`,
+    /// putting those units back and returning the deposits.
+    fn refund_reservations(&mut self, key: &str) {
+        let mut reservation_ids: Vec<u128> = self
+            .reservations
+            .iter()
+            .filter(|(_, reservation)| {
+                reservation.name == key
+                    || self.bundles.get(&reservation.name).is_some_and(|bundle| {
+                        bundle
+                            .components
+                            .iter()
+                            .any(|(component, _)| component == key)
+                    })
+            })
+            .map(|(reservation_id, _)| *reservation_id)
+            .collect();
+        reservation_ids.sort_unstable();
+        for reservation_id in reservation_ids {
+            let reservation = self
+                .reservations
+                .remove(&reservation_id)
+                .expect("The reservation was just found");
+            self.return_reserved_stock(&reservation.name, reservation.quantity, Some(key));
+            self.send_value(reservation.buyer, reservation.deposit);
+        }
+    }
+    /// Cancels and fully refunds the orders still waiting in the product's preorder queue, which
+    /// nothing can fill once it's gone.
+    fn refund_preorders(&mut self, key: &str) {
+        for order_id in self.preorders.remove(key).unwrap_or_default() {
+            let Some(buyer) = self.order_index.get(&order_id).copied() else {
+                continue;
+            };
+            let waiting = self
+                .purchase_mut(buyer, order_id)
+                .is_some_and(|purchase| matches!(purchase.status, Status::Preordered));
+            // Refunding fails if the proceeds were paid out already, leaving the order preordered
+            // for the admin to settle.
+            if waiting {
+                let _ = self.refund_order(
+                    buyer,
+                    order_id,
+                    Status::Cancelled,
+                    false,
+                    FULL_REFUND_PERCENT,
+                );
+            }
+        }
+    }
+    fn complete_reservation(
+        &mut self,
+        msg_source: ActorId,
+        msg_value: u128,
+        reservation_id: u128,
+    ) -> Result<MarketEvent, MarketError> {
+        let reservation = self
+            .reservations
+            .get(&reservation_id)
+            .filter(|reservation| reservation.buyer == msg_source)
+            .cloned()
+            .ok_or(MarketError::ThereIsNoSuchReservation)?;
+
+        // The deposit is already held, so it's paid in along with the attached value; `buy`
+        // changes nothing if it fails, and the reservation is then kept as it was.
+        self.reservations.remove(&reservation_id);
+        self.move_reserved_stock(&reservation.name, reservation.quantity, true);
+        let result = self.buy(
+            msg_source,
+            msg_value.saturating_add(reservation.deposit),
+            reservation.name.clone(),
+            reservation.quantity,
+            reservation.delivery_address.clone(),
+            None,
+            None,
+            false,
+            false,
+            0,
+        );
+        if result.is_err() {
+            self.move_reserved_stock(&reservation.name, reservation.quantity, false);
+            self.reservations.insert(reservation_id, reservation);
+        }
+        result
+    }
+    fn cancel_reservation(
+        &mut self,
+        msg_source: ActorId,
+        reservation_id: u128,
+    ) -> Result<MarketEvent, MarketError> {
+        let reservation = self
+            .reservations
+            .get(&reservation_id)
+            .filter(|reservation| reservation.buyer == msg_source)
+            .cloned()
+            .ok_or(MarketError::ThereIsNoSuchReservation)?;
+
+        self.reservations.remove(&reservation_id);
+        // Quoted with the units back in stock, as completing the reservation would be.
+        let released = self.release_reserved_stock(&reservation.name, reservation.quantity, None);
+        let completable = self
+            .quote(&reservation.name, reservation.quantity)
+            .is_ok_and(|quote| quote.total <= reservation.total);
+        for (product_name, previous_stock) in released {
+            self.stock_added(&product_name, previous_stock);
+        }
+        let refund = if completable {
+            *self.pending_payouts.entry(self.admin).or_default() += reservation.deposit;
+            0
+        } else {
+            self.send_value(msg_source, reservation.deposit);
+            reservation.deposit
+        };
+
+        Ok(MarketEvent::ReservationCancelled {
+            reservation_id,
+            refund,
+        })
+    }
+    fn forfeit_reservation(&mut self, reservation_id: u128) -> Result<MarketEvent, MarketError> {
+        let reservation = self
+            .reservations
+            .get(&reservation_id)
+            .ok_or(MarketError::ThereIsNoSuchReservation)?;
+        // Anyone may forfeit an expired reservation in case the scheduled message didn't make it.
+        let current_block: u64 = exec::block_height().into();
+        if msg::source() != exec::program_id() && current_block < reservation.expires_at {
+            return Err(MarketError::ReservationNotExpired);
+        }
+
+        let reservation = self
+            .reservations
+            .remove(&reservation_id)
+            .expect("The reservation was just found");
+        self.return_reserved_stock(&reservation.name, reservation.quantity, None);
+        *self.pending_payouts.entry(self.admin).or_default() += reservation.deposit;
+
+        Ok(MarketEvent::DepositForfeited {
+            reservation_id,
+            buyer: reservation.buyer,
+            deposit: reservation.deposit,
+        })
+    }
+    fn purchase_mut(&mut self, buyer: ActorId, order_id: u128) -> Option<&mut PurchaseData> {
+        self.purchases
+            .get_mut(&buyer)?
+            .iter_mut()
+            .find(|purchase| purchase.order_id == order_id)
+    }
+    fn cancel_order(
+        &mut self,
+        msg_source: ActorId,
+        order_id: u128,
+    ) -> Result<MarketEvent, MarketError> {
+        let refund_window = self.config.refund_window_blocks;
+        let purchase = self
+            .purchase_mut(msg_source, order_id)
+            .ok_or(MarketError::ThereIsNoSuchOrder)?;
+        let holds_stock = match purchase.status {
+            Status::PaidFor => true,
+            Status::Preordered => false,
+            _ => return Err(MarketError::OrderNotCancellable),
+        };
+        let current_block: u64 = exec::block_height().into();
+        let age = current_block.saturating_sub(purchase.block);
+        if age > refund_window {
+            return Err(MarketError::RefundWindowClosed);
+        }
+        let key = purchase.name.clone();
+        // The policy in force when the order is cancelled applies.
+        let product_percent = self
+            .products
+            .get(&key)
+            .map_or(FULL_REFUND_PERCENT, |product_data| {
+                product_data.refund_percent
+            });
+        let refund_percent = (u32::from(product_percent)
+            * u32::from(refund_tier_percent(&self.config.refund_tiers, age))
+            / u32::from(FULL_REFUND_PERCENT)) as u16;
+
+        let refund = self.refund_order(
+            msg_source,
+            order_id,
+            Status::Cancelled,
+            holds_stock,
+            refund_percent,
+        )?;
+
+        Ok(MarketEvent::OrderCancelled {
+            order_id,
+            refund,
+            refund_percent,
+        })
+    }
+    fn mark_delivery_failed(&mut self, order_id: u128) -> Result<MarketEvent, MarketError> {
+        self.check_admin("MarkDeliveryFailed")?;
+        let buyer = *self
+            .order_index
+            .get(&order_id)
+            .ok_or(MarketError::ThereIsNoSuchOrder)?;
+        let purchase = self
+            .purchase_mut(buyer, order_id)
+            .ok_or(MarketError::ThereIsNoSuchOrder)?;
+        if !matches!(purchase.status, Status::PaidFor | Status::Shipped) {
+            return Err(MarketError::IllegalStatusTransition);
+        }
+
+        let refund = self.refund_order(
+            buyer,
+            order_id,
+            Status::DeliveryFailed,
+            true,
+            FULL_REFUND_PERCENT,
+        )?;
+
+        Ok(MarketEvent::DeliveryFailedRefunded {
+            order_id,
+            buyer,
+            refund,
+        })
+    }
+    /// Gives the buyer of an order back `refund_percent` basis points of the value and store
+    /// credit it took, returns its stock if it `holds_stock` and moves it to the terminal
+    /// `status`. Returns the refunded value.
+    fn refund_order(
         &mut self,
-        name: String,
-        quantity: u128,
-        price: u128,
-    ) -> Result<MarketEvent, MarketError> {
-        let msg_source = msg::source();
-        if msg_source != self.admin {
-            return Err(MarketError::NotAdmin);
+        buyer: ActorId,
+        order_id: u128,
+        status: Status,
+        holds_stock: bool,
+        refund_percent: u16,
+    ) -> Result<u128, MarketError> {
+        let rounding = self.config.rounding;
+        let share = |amount: u128| {
+            rounding
+                .mul_div(amount, refund_percent.into(), FULL_REFUND_PERCENT.into())
+                .unwrap_or_else(|| {
+                    amount / u128::from(FULL_REFUND_PERCENT) * u128::from(refund_percent)
+                })
+                .min(amount)
+        };
+        let purchase = self
+            .purchase_mut(buyer, order_id)
+            .ok_or(MarketError::ThereIsNoSuchOrder)?;
+        let (key, quantity, refund, credit_used, points_redeemed, points_earned) = (
+            purchase.name.clone(),
+            purchase.quantity,
+            share(purchase.total_paid),
+            share(purchase.credit_used),
+            share(purchase.points_redeemed),
+            purchase.points_earned,
+        );
+
+        let proceeds = self.pending_payouts.entry(self.admin).or_default();
+        *proceeds = proceeds
+            .checked_sub(refund)
+            .ok_or(MarketError::ProceedsAlreadyPaidOut)?;
+
+        if let Some(spent) = self.total_spent.get_mut(&buyer) {
+            *spent = spent.saturating_sub(refund);
         }
-        if self.products.contains_key(&name) {
-            return Err(MarketError::AlreadyExists);
+        if let Some(purchase) = self.purchase_mut(buyer, order_id) {
+            set_status(purchase, status);
         }
-        if price < exec::env_vars().existential_deposit {
-            return Err(MarketError::PriceLessThanExistentialDeposit);
+        if credit_used != 0 {
+            let credit = self.credits.entry(buyer).or_default();
+            *credit = credit.saturating_add(credit_used);
+        }
+        // Points earned by the order may have been spent since; those are gone.
+        let points = self.loyalty_points.entry(buyer).or_default();
+        *points = points
+            .saturating_add(points_redeemed)
+            .saturating_sub(points_earned);
+        if *points == 0 {
+            self.loyalty_points.remove(&buyer);
+        }
+        // Products or bundles deleted since the purchase have no stock to return to.
+        let stock_lines = self
+            .stock_lines(&key, quantity)
+            .map_or(Vec::new(), |(_, stock_lines)| stock_lines);
+        for (product_name, returned) in stock_lines {
+            if let Some(product_data) = self.product_mut(&product_name) {
+                product_data.units_sold = product_data.units_sold.saturating_sub(returned);
+                if holds_stock {
+                    let previous_stock = product_data.quantity;
+                    product_data.quantity += returned;
+                    self.record_stock_change(&product_name, stock_delta(0, returned));
+                    self.stock_added(&product_name, previous_stock);
+                }
+            }
         }
 
-        let product_data = ProductData { quantity, price };
-        self.products.insert(name.clone(), product_data);
+        self.send_value(buyer, refund);
 
-        Ok(MarketEvent::ProductAdded {
-            name,
-            quantity,
-            price,
-        })
+        Ok(refund)
     }
-    fn update_product_info(
+    fn transfer_purchase(
         &mut self,
-        name: String,
-        quantity: Option<u128>,
-        price: Option<u128>,
+        msg_source: ActorId,
+        order_id: u128,
+        to: ActorId,
     ) -> Result<MarketEvent, MarketError> {
-        let msg_source = msg::source();
-        if msg_source != self.admin {
-            return Err(MarketError::NotAdmin);
+        let purchase = self
+            .purchase_mut(msg_source, order_id)
+            .ok_or(MarketError::ThereIsNoSuchOrder)?;
+        if !matches!(purchase.status, Status::PaidFor | Status::Preordered)
+            || to == ActorId::zero()
+            || to == msg_source
+        {
+            return Err(MarketError::OrderNotTransferable);
         }
-        let product_data = self
-            .products
-            .get_mut(&name)
-            .ok_or(MarketError::ThereIsNoSuchName)?;
+        // The order stays open, so the recipient takes it on as if placing it.
+        self.check_not_blocked(to)?;
+        self.check_open_orders(to, 1)?;
 
-        if let Some(quantity) = quantity {
-            product_data.quantity = quantity;
+        let purchases = self.purchases.entry(msg_source).or_default();
+        let Some(position) = purchases
+            .iter()
+            .position(|purchase| purchase.order_id == order_id)
+        else {
+            return Err(MarketError::ThereIsNoSuchOrder);
+        };
+        let purchase = purchases.remove(position);
+        if purchases.is_empty() {
+            self.purchases.remove(&msg_source);
         }
-        if let Some(price) = price {
-            product_data.price = price;
+        // What was paid counts as the recipient's spending now, as a refund will be theirs.
+        if let Some(spent) = self.total_spent.get_mut(&msg_source) {
+            *spent = spent.saturating_sub(purchase.total_paid);
         }
+        let spent = self.total_spent.entry(to).or_default();
+        *spent = spent.saturating_add(purchase.total_paid);
+        self.purchases.entry(to).or_default().push(purchase);
+        self.order_index.insert(order_id, to);
+        self.trim_history(to);
 
-        Ok(MarketEvent::ProductInfoUpdated {
-            name,
-            quantity,
-            price,
+        Ok(MarketEvent::PurchaseTransferred {
+            order_id,
+            from: msg_source,
+            to,
         })
     }
-    fn update_config(&mut self, config: Config) -> Result<MarketEvent, MarketError> {
-        let msg_source = msg::source();
-        if msg_source != self.admin {
-            return Err(MarketError::NotAdmin);
+    fn update_purchase_status(
+        &mut self,
+        order_id: u128,
+        status: Status,
+        tracking_number: Option<String>,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("UpdatePurchaseStatus")?;
+        if let Some(tracking_number) = &tracking_number {
+            if !matches!(status, Status::Shipped)
+                || tracking_number.is_empty()
+                || tracking_number.len() > self.config.max_tracking_len as usize
+            {
+                return Err(MarketError::InvalidTrackingNumber);
+            }
         }
-        self.config = config.clone();
-        Ok(MarketEvent::ConfigUpdated { config })
+        self.set_purchase_status(order_id, status.clone(), tracking_number)?;
+
+        Ok(MarketEvent::PurchaseStatusUpdated { order_id, status })
     }
-    fn delete_product(&mut self, name: String) -> Result<MarketEvent, MarketError> {
-        let msg_source = msg::source();
-        if msg_source != self.admin {
-            return Err(MarketError::NotAdmin);
-        }
+    fn update_purchase_status_batch(
+        &mut self,
+        updates: Vec<(u128, Status)>,
+    ) -> Result<MarketEvent, MarketError> {
+        self.check_admin("UpdatePurchaseStatusBatch")?;
+        self.check_batch_size(updates.len())?;
 
-        self.products
-            .remove(&name)
-            .ok_or(MarketError::ThereIsNoSuchName)?;
+        let mut succeeded = Vec::new();
+        let mut failed = Vec::new();
+        for (order_id, status) in updates {
+            match self.set_purchase_status(order_id, status, None) {
+                Ok(()) => succeeded.push(order_id),
+                Err(error) => failed.push((order_id, error)),
+            }
+        }
 
-        Ok(MarketEvent::ProductDeleted { name })
+        Ok(MarketEvent::StatusBatchUpdated { succeeded, failed })
     }
-    fn buy(
+    fn set_purchase_status(
         &mut self,
-        msg_source: ActorId,
-        msg_value: u128,
-        name: String,
-        quantity: u128,
-        delivery_address: String,
-    ) -> Result<MarketEvent, MarketError> {
-        let Some(product_data) = self.products.get_mut(&name) else {
-            return Err(MarketError::ThereIsNoSuchName);
+        order_id: u128,
+        status: Status,
+        tracking_number: Option<String>,
+    ) -> Result<(), MarketError> {
+        let buyer = *self
+            .order_index
+            .get(&order_id)
+            .ok_or(MarketError::ThereIsNoSuchOrder)?;
+        let purchase = self
+            .purchase_mut(buyer, order_id)
+            .ok_or(MarketError::ThereIsNoSuchOrder)?;
+        if !matches!(
+            (&purchase.status, &status),
+            (Status::PaidFor, Status::Shipped) | (Status::Shipped, Status::Delivered)
+        ) {
+            return Err(MarketError::IllegalStatusTransition);
+        }
+
+        set_status(purchase, status);
+        if tracking_number.is_some() {
+            purchase.tracking_number = tracking_number;
+        }
+        Ok(())
+    }
+    fn forget_history(&mut self, msg_source: ActorId) -> Result<MarketEvent, MarketError> {
+        let mut removed_count = 0;
+        if let Some(purchases) = self.purchases.get_mut(&msg_source) {
+            let order_index = &mut self.order_index;
+            purchases.retain(|purchase| {
+                let terminal = matches!(
+                    purchase.status,
+                    Status::Delivered | Status::Cancelled | Status::DeliveryFailed
+                );
+                if terminal {
+                    order_index.remove(&purchase.order_id);
+                    removed_count += 1;
+                }
+                !terminal
+            });
+            if purchases.is_empty() {
+                self.purchases.remove(&msg_source);
+            }
+        }
+
+        Ok(MarketEvent::HistoryForgotten { removed_count })
+    }
+    /// Drops `actor`'s oldest finished orders past `max_history_per_buyer`, notifying them.
+    fn trim_history(&mut self, actor: ActorId) {
+        let Some(max) = self.config.max_history_per_buyer else {
+            return;
         };
-        if quantity == 0 {
-            return Err(MarketError::ZeroQuantity);
+        let Some(purchases) = self.purchases.get_mut(&actor) else {
+            return;
+        };
+        let mut excess = purchases.len().saturating_sub(max as usize);
+        let mut removed_count = 0;
+        let order_index = &mut self.order_index;
+        // Purchases are kept in the order they were added, so the first finished ones go first.
+        purchases.retain(|purchase| {
+            let evict = excess != 0
+                && matches!(
+                    purchase.status,
+                    Status::Delivered | Status::Cancelled | Status::DeliveryFailed
+                );
+            if evict {
+                order_index.remove(&purchase.order_id);
+                excess -= 1;
+                removed_count += 1;
+            }
+            !evict
+        });
+        if purchases.is_empty() {
+            self.purchases.remove(&actor);
         }
-        if quantity > product_data.quantity {
-            return Err(MarketError::QuantityExceeded);
+        if removed_count != 0 {
+            let notification = self.sequence(MarketEvent::HistoryTrimmed { removed_count });
+            msg::send(actor, notification, 0).expect("Error in sending a notification");
+        }
+    }
+    fn send_value(&self, destination: ActorId, value: u128) {
+        if value != 0 {
+            msg::send_with_gas(destination, "", self.config.value_send_gas, value)
+                .expect("Error in sending value");
+        }
+    }
+    fn new_order_id(&mut self, buyer: ActorId) -> u128 {
+        let mut order_id = self.next_order_id;
+        if self.config.order_id_mode == OrderIdMode::Hashed {
+            let block: u64 = exec::block_height().into();
+            let mut nonce = self.next_order_id;
+            // The random seed keeps the ids from being recomputed by anyone who knows the count.
+            let subject: [u8; 32] = Blake2b::<U32>::digest((buyer, block, nonce).encode()).into();
+            let (seed, _) = exec::random(subject).expect("Unable to get a random seed");
+            // A collision is all but impossible, but would merge two orders in `order_index`.
+            loop {
+                order_id = hashed_order_id(buyer, block, nonce, seed);
+                if !self.order_index.contains_key(&order_id) {
+                    break;
+                }
+                nonce = nonce.wrapping_add(1);
+            }
+        }
+        self.next_order_id += 1;
+        order_id
+    }
+    /// Value the market holds on behalf of others.
+    fn held_value(&self) -> u128 {
+        self.pending_payouts
+            .values()
+            .fold(0u128, |held, amount| held.saturating_add(*amount))
+            .saturating_add(self.deposits())
+            .saturating_add(self.pending_buy_value)
+    }
+    fn deposits(&self) -> u128 {
+        self.reservations.values().fold(0, |held, reservation| {
+            held.saturating_add(reservation.deposit)
+        })
+    }
+    /// Value paid for orders their buyers may still cancel for a refund.
+    fn escrowed(&self) -> u128 {
+        let current_block: u64 = exec::block_height().into();
+        self.purchases
+            .values()
+            .flatten()
+            .filter(|purchase| {
+                matches!(purchase.status, Status::PaidFor | Status::Preordered)
+                    && current_block.saturating_sub(purchase.block)
+                        <= self.config.refund_window_blocks
+            })
+            .fold(0u128, |escrowed, purchase| {
+                escrowed.saturating_add(purchase.total_paid)
+            })
+    }
+    fn payout(&mut self, seller: ActorId) -> Result<MarketEvent, MarketError> {
+        let pending = self
+            .pending_payouts
+            .get(&seller)
+            .copied()
+            .unwrap_or_default();
+        // Proceeds are all the admin's, so theirs cover the refunds still owed.
+        let held_back = if seller == self.admin {
+            self.escrowed().min(pending)
+        } else {
+            0
+        };
+        let amount = pending - held_back;
+        if amount == 0 {
+            return Err(MarketError::NothingToPayout);
         }
 
-        let total_payment = product_data.price * quantity;
-        if msg_value < total_payment {
-            return Err(MarketError::InsufficientValue);
-        } else if msg_value > total_payment {
-            send_value(msg_source, msg_value - total_payment);
+        if held_back == 0 {
+            self.pending_payouts.remove(&seller);
+        } else {
+            self.pending_payouts.insert(seller, held_back);
         }
+        self.send_value(seller, amount);
 
-        product_data.quantity -= quantity;
+        Ok(MarketEvent::PayoutSent { seller, amount })
+    }
+    fn block_actor(&mut self, actor: ActorId) -> Result<MarketEvent, MarketError> {
+        self.check_admin("BlockActor")?;
+        self.blocklist.insert(actor, true);
 
-        let new_purchase = PurchaseData {
-            name: name.clone(),
-            quantity,
-            status: Status::PaidFor,
-            delivery_address,
-        };
-        self.purchases
-            .entry(msg_source)
-            .and_modify(|purchase| purchase.push(new_purchase.clone()))
-            .or_insert(vec![new_purchase]);
+        Ok(MarketEvent::ActorBlocked { actor })
+    }
+    fn unblock_actor(&mut self, actor: ActorId) -> Result<MarketEvent, MarketError> {
+        self.check_admin("UnblockActor")?;
+        self.blocklist.remove(&actor);
 
-        Ok(MarketEvent::Bought {
-            buyer: msg_source,
-            name,
-            quantity,
+        Ok(MarketEvent::ActorUnblocked { actor })
+    }
+    fn grant_credit(&mut self, actor: ActorId, amount: u128) -> Result<MarketEvent, MarketError> {
+        self.check_admin("GrantCredit")?;
+        if amount == 0 {
+            return Err(MarketError::ZeroQuantity);
+        }
+        let credit = self.credits.entry(actor).or_default();
+        *credit = credit.saturating_add(amount);
+
+        Ok(MarketEvent::CreditGranted {
+            actor,
+            amount,
+            balance: *credit,
         })
     }
+    fn sweep_unaccounted_funds(&mut self, to: ActorId) -> Result<MarketEvent, MarketError> {
+        self.check_admin("SweepUnaccountedFunds")?;
+        // The existential deposit stays behind so the market's account is never reaped.
+        let amount = exec::value_available()
+            .checked_sub(self.held_value())
+            .and_then(|unaccounted| unaccounted.checked_sub(exec::env_vars().existential_deposit))
+            .ok_or(MarketError::BalanceBelowObligations)?;
+        if amount == 0 {
+            return Err(MarketError::NothingToSweep);
+        }
+
+        self.send_value(to, amount);
+
+        Ok(MarketEvent::FundsSwept { to, amount })
+    }
+}
+
+fn expired(product_data: &ProductData) -> bool {
+    product_data
+        .expires_at
+        .is_some_and(|expires_at| exec::block_timestamp() >= expires_at)
+}
+
+fn hashed_order_id(buyer: ActorId, block: u64, nonce: u128, seed: [u8; 32]) -> u128 {
+    let hash: [u8; 32] = Blake2b::<U32>::digest((buyer, block, nonce, seed).encode()).into();
+    let mut order_id = [0; 16];
+    order_id.copy_from_slice(&hash[..16]);
+    u128::from_le_bytes(order_id)
+}
+
+fn receipt_hash(receipt: &Receipt) -> [u8; 32] {
+    let fields = (
+        receipt.order_id,
+        receipt.buyer,
+        &receipt.name,
+        receipt.unit_price,
+        receipt.quantity,
+        receipt.total_paid,
+        receipt.change,
+        receipt.tip,
+        receipt.block,
+        receipt.timestamp,
+    );
+    Blake2b::<U32>::digest(fields.encode()).into()
+}
+
+/// Price a unit of the product sells for in the current block.
+fn current_price(product_data: &ProductData) -> u128 {
+    match &product_data.flash_sale {
+        Some(flash_sale) if flash_sale.ends_at > exec::block_height().into() => {
+            flash_sale.sale_price
+        }
+        _ => product_data.price,
+    }
 }
 
-fn send_value(destination: ActorId, value: u128) {
-    if value != 0 {
-        msg::send_with_gas(destination, "", 0, value).expect("Error in sending value");
+/// Unit price of the product when buying `quantity` units: the lowest of the current price and
+/// the prices of the tiers the quantity qualifies for.
+fn unit_price(product_data: &ProductData, quantity: u128) -> u128 {
+    product_data
+        .price_tiers
+        .iter()
+        .filter(|tier| quantity >= tier.min_quantity)
+        .map(|tier| tier.unit_price)
+        .fold(current_price(product_data), u128::min)
+}
+
+/// Signed change in stock from `from` to `to` units, saturating at the bounds of `i128`.
+fn stock_delta(from: u128, to: u128) -> i128 {
+    if to >= from {
+        i128::try_from(to - from).unwrap_or(i128::MAX)
+    } else {
+        i128::try_from(from - to).map_or(i128::MIN, |delta| -delta)
     }
 }
 
-#[no_mangle]
-extern "C" fn handle() {
+/// Counts `quantity` new units towards the product's lifetime supply.
+fn mint(product_data: &mut ProductData, quantity: u128) -> Result<(), MarketError> {
+    product_data.total_minted = minted(product_data, quantity)?;
+    Ok(())
+}
+
+/// The product's lifetime supply after minting `quantity` more units, if its max supply allows.
+fn minted(product_data: &ProductData, quantity: u128) -> Result<u128, MarketError> {
+    let total_minted = product_data
+        .total_minted
+        .checked_add(quantity)
+        .ok_or(MarketError::MaxSupplyExceeded)?;
+    if product_data
+        .max_supply
+        .is_some_and(|max_supply| total_minted > max_supply)
+    {
+        return Err(MarketError::MaxSupplyExceeded);
+    }
+    Ok(total_minted)
+}
+
+/// Basis points of an order `age` blocks old that `tiers` let be refunded, see
+/// `Config::refund_tiers`.
+fn refund_tier_percent(tiers: &[(u64, u16)], age: u64) -> u16 {
+    if tiers.is_empty() {
+        return FULL_REFUND_PERCENT;
+    }
+    tiers
+        .iter()
+        .find(|(max_age, _)| age <= *max_age)
+        .map_or(0, |(_, refund_percent)| *refund_percent)
+}
+
+fn validate_config(config: &Config) -> Result<(), MarketError> {
+    if config.public_key.is_empty()
+        || config.value_send_gas > MAX_VALUE_SEND_GAS
+        || config.currency_symbol.len() > MAX_CURRENCY_SYMBOL_LEN
+        || config.decimals > MAX_DECIMALS
+        || config.restock_increment == Some(0)
+        || (config.confirm_stock_with_oracle && config.inventory_oracle.is_none())
+        || config.reservation_blocks == Some(0)
+        || config.min_deposit_percent > FULL_REFUND_PERCENT
+        || config.refund_tiers.len() > MAX_REFUND_TIERS
+        || config
+            .refund_tiers
+            .windows(2)
+            .any(|pair| pair[0].0 >= pair[1].0)
+        || config
+            .refund_tiers
+            .iter()
+            .any(|(_, refund_percent)| *refund_percent > FULL_REFUND_PERCENT)
+    {
+        return Err(MarketError::InvalidConfig);
+    }
+    Ok(())
+}
+
+/// Replies exactly once, after the action has been handled. Nothing before the final
+/// `msg::reply` may reply: notifications and value transfers go out with `msg::send*` as separate
+/// messages, so the reply can't have been committed already and failing to send it is a bug.
+/// The only wait is for the inventory oracle, before a purchase changes anything.
+#[gstd::async_main]
+async fn main() {
+    let gas_at_start = exec::gas_available();
     let action: MarketAction = msg::load().expect("Could not load `MarketAction`.");
     let market: &mut Market =
         unsafe { MARKET.as_mut().expect("Unexpected uninitialized `MARKET`.") };
+    market.apply_price_changes();
     let result = match action {
         MarketAction::AddProduct {
             name,
             quantity,
             price,
-        } => market.add_product(name, quantity, price),
+            max_supply,
+            preorder,
+            sku,
+            instant,
+            delivery_payload,
+        } => market.add_product(
+            name,
+            quantity,
+            price,
+            max_supply,
+            preorder,
+            sku,
+            instant,
+            delivery_payload,
+        ),
+        MarketAction::RestockProduct { name, quantity } => market.restock_product(name, quantity),
+        MarketAction::RestockAll { quantity, delta } => market.restock_all(quantity, delta),
+        MarketAction::SubscribeRestock { name } => market.subscribe_restock(msg::source(), name),
+        MarketAction::UnsubscribeRestock { name } => {
+            market.unsubscribe_restock(msg::source(), name)
+        }
+        MarketAction::SetProductCategory { name, category } => {
+            market.set_product_category(name, category)
+        }
+        MarketAction::RenameCategory { from, into } => market.rename_category(from, into),
+        MarketAction::FreezeProduct { name, frozen } => market.freeze_product(name, frozen),
+        MarketAction::StartFlashSale {
+            name,
+            sale_price,
+            duration_blocks,
+        } => market.start_flash_sale(name, sale_price, duration_blocks),
+        MarketAction::EndFlashSale { name } => market.end_flash_sale(name),
+        MarketAction::SetProductTranslation {
+            name,
+            lang,
+            translation,
+        } => market.set_product_translation(name, lang, translation),
+        MarketAction::SetAllowedCountries { name, countries } => {
+            market.set_allowed_countries(name, countries)
+        }
+        MarketAction::SetProductAttribute { name, key, value } => {
+            market.set_product_attribute(name, key, value)
+        }
+        MarketAction::RemoveProductAttribute { name, key } => {
+            market.remove_product_attribute(name, key)
+        }
+        MarketAction::SetPurchaseCooldown {
+            name,
+            cooldown_blocks,
+        } => market.set_purchase_cooldown(name, cooldown_blocks),
+        MarketAction::SetProductExpiry { name, expires_at } => {
+            market.set_product_expiry(name, expires_at)
+        }
+        MarketAction::SetFeatured(names) => market.set_featured(names),
+        MarketAction::AddFeatured { name } => market.add_featured(name),
+        MarketAction::RemoveFeatured { name } => market.remove_featured(name),
+        MarketAction::AddAllowlistedProduct {
+            name,
+            quantity,
+            price,
+            allowlist,
+        } => market.add_allowlisted_product(name, quantity, price, allowlist),
+        MarketAction::LiftAllowlist { name } => market.lift_allowlist(name),
+        MarketAction::SetRefundPercent {
+            name,
+            refund_percent,
+        } => market.set_refund_percent(name, refund_percent),
+        MarketAction::SetPriceTiers { name, tiers } => market.set_price_tiers(name, tiers),
+        MarketAction::SetProductCost { name, cost } => market.set_product_cost(name, cost),
+        MarketAction::SetUnitScale { name, unit_scale } => market.set_unit_scale(name, unit_scale),
+        MarketAction::AddBundle {
+            name,
+            components,
+            price,
+        } => market.add_bundle(name, components, price),
         MarketAction::UpdateProductInfo {
             name,
             quantity,
             price,
         } => market.update_product_info(name, quantity, price),
+        MarketAction::SchedulePriceChange {
+            name,
+            new_price,
+            effective_block,
+        } => market.schedule_price_change(name, new_price, effective_block),
+        MarketAction::CompareAndUpdateProduct {
+            name,
+            expected_quantity,
+            expected_price,
+            new_quantity,
+            new_price,
+        } => market.compare_and_update_product(
+            name,
+            expected_quantity,
+            expected_price,
+            new_quantity,
+            new_price,
+        ),
         MarketAction::UpdateConfig { config } => market.update_config(config),
         MarketAction::DeleteProduct { name } => market.delete_product(name),
+        MarketAction::RevalidatePrices => market.revalidate_prices(),
+        MarketAction::DeleteProductsBatch(names) => market.delete_products_batch(names),
+        MarketAction::MergeProducts { from, into } => market.merge_products(from, into),
         MarketAction::Buy {
             name,
             quantity,
             delivery_address,
+            contact,
+            max_unit_price,
+            exact,
+            use_credit,
+            redeem_points,
+            verbose,
+        } => {
+            let msg_source = msg::source();
+            let msg_value = msg::value();
+            // Other messages may be handled while waiting, so prices are brought up to date and
+            // `buy` checks everything afresh.
+            market.pending_buy_value += msg_value;
+            let confirmed = market.confirm_stock_with_oracle(&name, quantity).await;
+            market.pending_buy_value -= msg_value;
+            let result = match confirmed {
+                Ok(()) => {
+                    market.apply_price_changes();
+                    market.buy(
+                        msg_source,
+                        msg_value,
+                        name,
+                        quantity,
+                        delivery_address,
+                        contact,
+                        max_unit_price,
+                        exact,
+                        use_credit,
+                        redeem_points,
+                    )
+                }
+                Err(error) => Err(error),
+            };
+            if result.is_err() {
+                market.send_value(msg_source, msg_value);
+            }
+            result.map(|event| match event {
+                MarketEvent::Bought { order_id, .. } if !verbose => {
+                    MarketEvent::OrderPlaced { order_id }
+                }
+                event => event,
+            })
+        }
+        MarketAction::BuyCart {
+            items,
+            delivery_address,
+        } => {
+            let msg_source = msg::source();
+            let msg_value = msg::value();
+            let result = market.buy_cart(msg_source, msg_value, items, delivery_address);
+            if result.is_err() {
+                market.send_value(msg_source, msg_value);
+            }
+            result
+        }
+        MarketAction::ReserveWithDeposit {
+            name,
+            quantity,
+            deposit,
+            delivery_address,
         } => {
             let msg_source = msg::source();
             let msg_value = msg::value();
-            let result = market.buy(msg_source, msg_value, name, quantity, delivery_address);
+            let result = market.reserve_with_deposit(
+                msg_source,
+                msg_value,
+                name,
+                quantity,
+                deposit,
+                delivery_address,
+            );
             if result.is_err() {
-                send_value(msg_source, msg_value);
+                market.send_value(msg_source, msg_value);
             }
             result
         }
+        MarketAction::CompleteReservation { reservation_id } => {
+            let msg_source = msg::source();
+            let msg_value = msg::value();
+            let result = market.complete_reservation(msg_source, msg_value, reservation_id);
+            if result.is_err() {
+                market.send_value(msg_source, msg_value);
+            }
+            result
+        }
+        MarketAction::ForfeitReservation { reservation_id } => {
+            market.forfeit_reservation(reservation_id)
+        }
+        MarketAction::CancelOrder { order_id } => market.cancel_order(msg::source(), order_id),
+        MarketAction::MarkDeliveryFailed { order_id } => market.mark_delivery_failed(order_id),
+        MarketAction::TransferPurchase { order_id, to } => {
+            market.transfer_purchase(msg::source(), order_id, to)
+        }
+        MarketAction::UpdatePurchaseStatus {
+            order_id,
+            status,
+            tracking_number,
+        } => market.update_purchase_status(order_id, status, tracking_number),
+        MarketAction::UpdatePurchaseStatusBatch(updates) => {
+            market.update_purchase_status_batch(updates)
+        }
+        MarketAction::ForgetMyHistory => market.forget_history(msg::source()),
+        MarketAction::Payout { seller } => market.payout(seller),
+        MarketAction::BlockActor { actor } => market.block_actor(actor),
+        MarketAction::UnblockActor { actor } => market.unblock_actor(actor),
+        MarketAction::GrantCredit { actor, amount } => market.grant_credit(actor, amount),
+        MarketAction::SweepUnaccountedFunds { to } => market.sweep_unaccounted_funds(to),
+        MarketAction::CancelReservation { reservation_id } => {
+            market.cancel_reservation(msg::source(), reservation_id)
+        }
     };
+    market.log_admin_action(result.is_ok());
 
+    let result = result.map(|event| {
+        let mut event = market.sequence(event);
+        if market.config.include_gas_diagnostics {
+            event.gas_used = Some(gas_at_start.saturating_sub(exec::gas_available()));
+        }
+        event
+    });
     msg::reply(result, 0)
-        .expect("Failed to encode or reply with `Result<MarketEvent, MarketError>`.");
+        .expect("Failed to encode or reply with `Result<SequencedEvent, MarketError>`.");
 }
 
 #[no_mangle]
 extern "C" fn state() {
-    let market = unsafe { MARKET.take().expect("Unexpected error in taking state") };
+    let mut market = unsafe { MARKET.take().expect("Unexpected error in taking state") };
     let query: StateQuery = msg::load().expect("Unable to load the state query");
+    market.apply_price_changes();
+    if market.config.hide_cost && !matches!(query, StateQuery::GetMarginReport) {
+        for product_data in market.products.values_mut() {
+            product_data.cost = None;
+        }
+    }
     let reply = match query {
-        StateQuery::All => StateReply::All(market.into()),
-        StateQuery::GetProducts => StateReply::Products(market.products.into_iter().collect()),
-        StateQuery::GetPurchases => StateReply::Purchases(market.purchases.into_iter().collect()),
+        StateQuery::All => {
+            let mut state: State = market.into();
+            fit_state(&mut state);
+            StateReply::All(state)
+        }
+        StateQuery::GetProducts => {
+            market
+                .products
+                .retain(|_, product_data| product_data.active);
+            let (products, truncated) = page(market.products, 0, MAX_REPLY_ITEMS);
+            StateReply::Products {
+                products,
+                truncated,
+            }
+        }
+        StateQuery::GetProductsWithQuantity(quantity) => {
+            market
+                .products
+                .retain(|_, product_data| product_data.quantity == quantity);
+            let (products, truncated) = page(market.products, 0, MAX_REPLY_ITEMS);
+            StateReply::Products {
+                products,
+                truncated,
+            }
+        }
+        StateQuery::GetProductsPage { offset, limit } => {
+            market
+                .products
+                .retain(|_, product_data| product_data.active);
+            let (products, truncated) = page(market.products, offset, limit);
+            StateReply::Products {
+                products,
+                truncated,
+            }
+        }
+        StateQuery::GetProductsSortedBy {
+            field,
+            descending,
+            offset,
+            limit,
+        } => {
+            let mut products: Vec<_> = market
+                .products
+                .into_iter()
+                .filter(|(_, product_data)| product_data.active)
+                .collect();
+            products.sort_unstable_by(|(key, product_data), (other_key, other_data)| {
+                let order = match field {
+                    SortField::Name => Ordering::Equal,
+                    SortField::Price => current_price(product_data).cmp(&current_price(other_data)),
+                    SortField::Quantity => product_data.quantity.cmp(&other_data.quantity),
+                    SortField::UnitsSold => product_data.units_sold.cmp(&other_data.units_sold),
+                }
+                .then_with(|| key.cmp(other_key));
+                if descending {
+                    order.reverse()
+                } else {
+                    order
+                }
+            });
+            let (products, truncated) = page_sorted(products, offset, limit);
+            StateReply::Products {
+                products,
+                truncated,
+            }
+        }
+        StateQuery::GetPurchases => {
+            let (purchases, truncated) = page(by_order_id(market.purchases), 0, MAX_REPLY_ITEMS);
+            StateReply::Purchases {
+                purchases,
+                truncated,
+            }
+        }
+        StateQuery::GetPurchasesPage { offset, limit } => {
+            let (purchases, truncated) = page(by_order_id(market.purchases), offset, limit);
+            StateReply::Purchases {
+                purchases,
+                truncated,
+            }
+        }
         StateQuery::GetActorPurchases(actor_id) => {
-            StateReply::ActorPurchases(market.purchases.get(&actor_id).or(None).cloned())
+            let purchases = market.purchases.get(&actor_id).cloned();
+            StateReply::ActorPurchases(ActorPurchases {
+                // Spend is kept after the orders are forgotten or trimmed.
+                known: purchases.is_some() || market.total_spent.contains_key(&actor_id),
+                purchases: purchases.unwrap_or_default(),
+            })
+        }
+        StateQuery::GetPurchasesForActors(actors) => {
+            let max_query_batch = market.config.max_query_batch as usize;
+            let mut purchases = market.purchases;
+            StateReply::Purchases {
+                truncated: actors.len() > max_query_batch,
+                purchases: actors
+                    .into_iter()
+                    .take(max_query_batch)
+                    .filter_map(|actor_id| purchases.remove_entry(&actor_id))
+                    .collect(),
+            }
+        }
+        StateQuery::GetOrphanedPurchases => {
+            let (products, bundles) = (&market.products, &market.bundles);
+            let orphaned = market
+                .purchases
+                .into_iter()
+                .filter_map(|(buyer, mut purchases)| {
+                    purchases.retain(|purchase| {
+                        !products.contains_key(&purchase.name)
+                            && !bundles.contains_key(&purchase.name)
+                    });
+                    (!purchases.is_empty()).then_some((buyer, purchases))
+                })
+                .collect();
+            let (purchases, truncated) = page(orphaned, 0, MAX_REPLY_ITEMS);
+            StateReply::Purchases {
+                purchases,
+                truncated,
+            }
+        }
+        StateQuery::GetInvoice(order_id) => StateReply::Invoice(invoice(&market, order_id)),
+        StateQuery::GetScheduledPriceChanges => StateReply::ScheduledPriceChanges(
+            market
+                .price_changes
+                .into_iter()
+                .map(|((effective_block, key), new_price)| (key, effective_block, new_price))
+                .collect(),
+        ),
+        StateQuery::GetReservation(reservation_id) => {
+            StateReply::Reservation(market.reservations.get(&reservation_id).cloned())
+        }
+        StateQuery::GetActiveReservations(buyer) => {
+            let current_block: u64 = exec::block_height().into();
+            let mut reservations: Vec<_> = market
+                .reservations
+                .into_iter()
+                .filter(|(_, reservation)| {
+                    reservation.buyer == buyer && current_block < reservation.expires_at
+                })
+                .collect();
+            reservations.sort_unstable_by_key(|(reservation_id, _)| *reservation_id);
+            StateReply::Reservations(reservations)
+        }
+        StateQuery::GetOrderTimeline(order_id) => StateReply::OrderTimeline(
+            market
+                .order_index
+                .get(&order_id)
+                .and_then(|buyer| market.purchases.get(buyer))
+                .and_then(|purchases| {
+                    purchases
+                        .iter()
+                        .find(|purchase| purchase.order_id == order_id)
+                })
+                .map(|purchase| purchase.status_history.clone()),
+        ),
+        StateQuery::GetStateSize => StateReply::StateSize {
+            products: encoded_size(&market.products),
+            purchases: encoded_size(&market.purchases),
+        },
+        StateQuery::QuoteBuy { name, quantity } => {
+            StateReply::Quote(market.quote(&market.product_key(&name), quantity))
+        }
+        StateQuery::SimulateBuy { name, quantity } => {
+            StateReply::BuySimulation(market.simulate_buy(&market.product_key(&name), quantity))
+        }
+        StateQuery::GetProduct { name, lang } => {
+            let key = market.product_key(&name);
+            let product = market
+                .products
+                .remove_entry(&key)
+                .map(|(key, mut product_data)| {
+                    if let Some(translation) = lang
+                        .and_then(|lang| product_data.translations.get(&lang))
+                        .cloned()
+                    {
+                        product_data.display_name = translation;
+                    }
+                    (key, product_data)
+                });
+            StateReply::Product(product)
+        }
+        StateQuery::CompareProducts(names) => {
+            let max_query_batch = market.config.max_query_batch as usize;
+            StateReply::ProductComparison {
+                products: names
+                    .iter()
+                    .take(max_query_batch)
+                    .map(|name| market.products.get(&market.product_key(name)).cloned())
+                    .collect(),
+                truncated: names.len() > max_query_batch,
+            }
+        }
+        StateQuery::GetFeatured => {
+            let mut products = market.products;
+            StateReply::Featured(
+                market
+                    .featured
+                    .iter()
+                    .filter_map(|key| products.remove_entry(key))
+                    .collect(),
+            )
+        }
+        StateQuery::GetProductBySku(sku) => {
+            let mut products = market.products;
+            StateReply::Product(
+                market
+                    .sku_index
+                    .get(&sku)
+                    .and_then(|key| products.remove_entry(key)),
+            )
+        }
+        StateQuery::GetLoyaltyPoints(actor) => StateReply::LoyaltyPoints(
+            market
+                .loyalty_points
+                .get(&actor)
+                .copied()
+                .unwrap_or_default(),
+        ),
+        StateQuery::IsBlocked(actor) => {
+            StateReply::Blocked(market.blocklist.get(&actor).copied().unwrap_or_default())
+        }
+        StateQuery::GetSellerBalance(seller) => StateReply::Balance(
+            market
+                .pending_payouts
+                .get(&seller)
+                .copied()
+                .unwrap_or_default(),
+        ),
+        StateQuery::GetProductsModifiedSince(block) => StateReply::ProductsModifiedSince {
+            products: market
+                .products
+                .into_iter()
+                .filter(|(_, product_data)| product_data.last_modified >= block)
+                .collect(),
+            deleted: market
+                .deleted_products
+                .into_iter()
+                .filter_map(|(key, deleted_at)| (deleted_at >= block).then_some(key))
+                .collect(),
+        },
+        StateQuery::GetExpiredProducts => {
+            let mut names: Vec<String> = market
+                .products
+                .into_iter()
+                .filter(|(_, product_data)| expired(product_data))
+                .map(|(key, _)| key)
+                .collect();
+            names.sort_unstable();
+            StateReply::ExpiredProducts(names)
+        }
+        StateQuery::GetUnsoldProducts => {
+            let mut names: Vec<String> = market
+                .products
+                .into_iter()
+                .filter(|(_, product_data)| product_data.units_sold == 0)
+                .map(|(key, _)| key)
+                .collect();
+            names.sort_unstable();
+            StateReply::UnsoldProducts(names)
+        }
+        StateQuery::GetCategories => StateReply::Categories(
+            market
+                .products
+                .values()
+                .filter_map(|product_data| product_data.category.clone())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+        ),
+        StateQuery::GetAdminActions { limit } => {
+            let mut admin_actions = market.admin_actions;
+            let first = admin_actions.len().saturating_sub(limit as usize);
+            StateReply::AdminActions(admin_actions.split_off(first))
+        }
+        StateQuery::GetInventorySnapshots { limit } => {
+            let mut snapshots = market.snapshots;
+            let first = snapshots.len().saturating_sub(limit as usize);
+            StateReply::InventorySnapshots(snapshots.split_off(first))
+        }
+        StateQuery::GetStockHistory(name) => StateReply::StockHistory(
+            market
+                .stock_history
+                .get(&market.product_key(&name))
+                .cloned()
+                .unwrap_or_default(),
+        ),
+        StateQuery::GetEventSeq => StateReply::EventSeq(market.event_seq),
+        StateQuery::GetMarginReport => StateReply::MarginReport(margin_report(&market.products)),
+        StateQuery::GetBalanceSheet => StateReply::BalanceSheet(balance_sheet(&market)),
+        StateQuery::GetCategoryStats(category) => {
+            StateReply::CategoryStats(category_stats(&market.products, &category))
+        }
+        StateQuery::GetConfig => StateReply::Config(market.config),
+        StateQuery::GetProductCount => StateReply::Count(market.products.len() as u32),
+        StateQuery::GetBuyerCount { exclude_cancelled } => StateReply::Count(
+            market
+                .purchases
+                .values()
+                .filter(|purchases| {
+                    !exclude_cancelled
+                        || purchases.iter().any(|purchase| {
+                            !matches!(purchase.status, Status::Cancelled | Status::DeliveryFailed)
+                        })
+                })
+                .count() as u32,
+        ),
+        StateQuery::GetActorContext(actor_id) => StateReply::ActorContext(ActorContext {
+            is_admin: actor_id == market.admin,
+            purchase_count: market
+                .purchases
+                .get(&actor_id)
+                .map_or(0, |purchases| purchases.len() as u32),
+            total_spent: market
+                .total_spent
+                .get(&actor_id)
+                .copied()
+                .unwrap_or_default(),
+        }),
+        StateQuery::ExportActorData(actor) => {
+            let mut restock_subscriptions: Vec<String> = market
+                .restock_subscribers
+                .iter()
+                .filter(|(_, subscribers)| subscribers.contains(&actor))
+                .map(|(key, _)| key.clone())
+                .collect();
+            restock_subscriptions.sort_unstable();
+            StateReply::ActorExport(ActorExport {
+                version: ACTOR_EXPORT_VERSION,
+                actor,
+                purchases: market.purchases.remove(&actor).unwrap_or_default(),
+                total_spent: market.total_spent.get(&actor).copied().unwrap_or_default(),
+                credit: market.credits.get(&actor).copied().unwrap_or_default(),
+                loyalty_points: market
+                    .loyalty_points
+                    .get(&actor)
+                    .copied()
+                    .unwrap_or_default(),
+                restock_subscriptions,
+                blocked: market.blocklist.get(&actor).copied().unwrap_or_default(),
+            })
         }
     };
     msg::reply(reply, 0).expect("Unable to share the state");
 }
 
+/// Sorts the entries by key and returns up to `limit` of them, at most `MAX_REPLY_ITEMS`,
+/// starting at `offset`, together with whether any entries follow.
+fn page<K: Ord, V>(map: HashMap<K, V>, offset: u32, limit: u32) -> (Vec<(K, V)>, bool) {
+    let mut entries: Vec<_> = map.into_iter().collect();
+    entries.sort_unstable_by(|(key, _), (other_key, _)| key.cmp(other_key));
+    page_sorted(entries, offset, limit)
+}
+
+/// Sorts each buyer's purchases by `order_id`; they're otherwise kept in the order they were
+/// added, which transfers and hashed order ids make arbitrary.
+fn by_order_id(
+    mut purchases: HashMap<ActorId, Vec<PurchaseData>>,
+) -> HashMap<ActorId, Vec<PurchaseData>> {
+    for purchases in purchases.values_mut() {
+        purchases.sort_unstable_by_key(|purchase| purchase.order_id);
+    }
+    purchases
+}
+
+/// Like `page`, for entries that are already sorted.
+fn page_sorted<T>(mut entries: Vec<T>, offset: u32, limit: u32) -> (Vec<T>, bool) {
+    let end = offset.saturating_add(limit.min(MAX_REPLY_ITEMS)) as usize;
+    let truncated = entries.len() > end;
+    entries.truncate(end);
+    let page = entries.split_off((offset as usize).min(entries.len()));
+    (page, truncated)
+}
+
+/// Halves the longer of the state's product and purchase lists until it fits in a reply.
+fn fit_state(state: &mut State) {
+    if state.encoded_size() <= MAX_STATE_REPLY_SIZE {
+        return;
+    }
+    state.complete = false;
+    state
+        .products
+        .sort_unstable_by(|(key, _), (other_key, _)| key.cmp(other_key));
+    state
+        .purchases
+        .sort_unstable_by(|(buyer, _), (other_buyer, _)| buyer.cmp(other_buyer));
+    while state.encoded_size() > MAX_STATE_REPLY_SIZE {
+        if state.products.len() >= state.purchases.len() && !state.products.is_empty() {
+            state.products.truncate(state.products.len() / 2);
+        } else if !state.purchases.is_empty() {
+            state.purchases.truncate(state.purchases.len() / 2);
+        } else {
+            break;
+        }
+    }
+}
+
+/// Moves the order to `status`, recording the change in its timeline.
+fn set_status(purchase: &mut PurchaseData, status: Status) {
+    purchase
+        .status_history
+        .push((exec::block_height().into(), status.clone()));
+    purchase.status = status;
+}
+
+fn invoice(market: &Market, order_id: u128) -> Option<Invoice> {
+    let buyer = *market.order_index.get(&order_id)?;
+    let purchase = market
+        .purchases
+        .get(&buyer)?
+        .iter()
+        .find(|purchase| purchase.order_id == order_id)?;
+    let line = InvoiceLine {
+        name: purchase.name.clone(),
+        quantity: purchase.quantity,
+        unit_price: purchase.unit_price,
+        total: purchase
+            .total_paid
+            .saturating_add(purchase.credit_used)
+            .saturating_add(purchase.points_redeemed),
+    };
+    Some(Invoice {
+        order_id,
+        buyer,
+        seller: market.admin,
+        subtotal: line.total,
+        total: purchase.total_paid,
+        lines: vec![line],
+        block: purchase.block,
+        timestamp: purchase.timestamp,
+    })
+}
+
+fn balance_sheet(market: &Market) -> BalanceSheet {
+    let contract_balance = exec::value_available();
+    let deposits = market.deposits();
+    let pending_buys = market.pending_buy_value;
+    let held = market.held_value();
+    let pending_payouts = held - deposits - pending_buys;
+    let escrowed = market.escrowed();
+    BalanceSheet {
+        contract_balance,
+        pending_payouts,
+        deposits,
+        pending_buys,
+        escrowed: escrowed.min(pending_payouts),
+        unaccounted: contract_balance.saturating_sub(held),
+    }
+}
+
+fn category_stats(
+    products: &HashMap<String, ProductData>,
+    category: &str,
+) -> Option<CategoryStats> {
+    let mut stats = CategoryStats::default();
+    for product_data in products
+        .values()
+        .filter(|product_data| product_data.category.as_deref() == Some(category))
+    {
+        stats.product_count += 1;
+        stats.total_stock = stats.total_stock.checked_add(product_data.quantity)?;
+        stats.total_units_sold = stats
+            .total_units_sold
+            .checked_add(product_data.units_sold)?;
+        stats.total_revenue = stats
+            .total_revenue
+            .checked_add(scaled_value(product_data, product_data.price)?)?;
+    }
+    Some(stats)
+}
+
+/// Value of the product's sold units at `per_unit` for each of its priced units, rounded down.
+fn scaled_value(product_data: &ProductData, per_unit: u128) -> Option<u128> {
+    RoundingMode::Floor.mul_div(product_data.units_sold, per_unit, product_data.unit_scale)
+}
+
+fn margin_report(products: &HashMap<String, ProductData>) -> Option<MarginReport> {
+    let (mut revenue, mut cost) = (0u128, 0u128);
+    for product_data in products.values() {
+        let Some(unit_cost) = product_data.cost else {
+            continue;
+        };
+        revenue = revenue.checked_add(scaled_value(product_data, product_data.price)?)?;
+        cost = cost.checked_add(scaled_value(product_data, unit_cost)?)?;
+    }
+    let margin = if revenue >= cost {
+        i128::try_from(revenue - cost).ok()?
+    } else {
+        i128::try_from(cost - revenue).ok()?.checked_neg()?
+    };
+    Some(MarginReport {
+        revenue,
+        cost,
+        margin,
+    })
+}
+
+fn encoded_size<K: Encode, V: Encode>(map: &HashMap<K, V>) -> u64 {
+    map.iter().map(|entry| entry.encoded_size() as u64).sum()
+}
+
 impl From<Market> for State {
     fn from(value: Market) -> Self {
         let Market {
             products,
             purchases,
+            bundles,
             admin,
             config,
+            pending_payouts,
+            total_spent,
+            next_order_id,
+            stock_history: _,
+            order_index: _,
+            preorders: _,
+            deleted_products: _,
+            sku_index: _,
+            event_seq: _,
+            snapshots: _,
+            last_buy: _,
+            restock_subscribers: _,
+            admin_actions: _,
+            admitted_action: _,
+            credits: _,
+            blocklist: _,
+            featured: _,
+            loyalty_points: _,
+            reservations: _,
+            next_reservation_id: _,
+            price_changes: _,
+            pending_buy_value: _,
         } = value;
 
         let products = products.into_iter().collect();
         let purchases = purchases.into_iter().collect();
+        let bundles = bundles.into_iter().collect();
+        let pending_payouts = pending_payouts.into_iter().collect();
+        let total_spent = total_spent.into_iter().collect();
+        let next_order_id = match config.order_id_mode {
+            OrderIdMode::Sequential => next_order_id,
+            OrderIdMode::Hashed => 0,
+        };
 
         Self {
+            schema_version: STATE_SCHEMA_VERSION,
             products,
             purchases,
+            bundles,
             admin,
             config,
+            pending_payouts,
+            total_spent,
+            next_order_id,
+            complete: true,
         }
     }
 }
@@ -1,5 +1,5 @@
-use gstd::{Encode};
-use gtest::{Program, System};
+use gstd::{Decode, Encode};
+use gtest::{Log, Program, RunResult, System};
 use market_io::*;
 
 pub const USERS: [u64; 3] = [10, 11, 12];
@@ -15,7 +15,7 @@ pub trait TestFunc {
 
 impl TestFunc for Program<'_> {
     fn add_product(&self, from: u64, name: String, quantity: u128, price: u128, error: Option<MarketError>) {
-        let result = self.send(from, MarketAction::AddProduct { name: name.clone(), quantity, price});
+        let result = self.send(from, MarketAction::AddProduct { name: name.clone(), quantity, price, max_supply: None, preorder: false, sku: String::new(), instant: false, delivery_payload: None });
         assert!(!result.main_failed());
         let reply = if let Some(error) = error {
             Err(error)
@@ -24,9 +24,13 @@ impl TestFunc for Program<'_> {
                 name,
                 quantity,
                 price,
+                max_supply: None,
+                preorder: false,
+                sku: String::new(),
+                instant: false,
             })
         };
-        assert!(result.contains(&(from, reply.encode())));
+        assert!(has_reply(&result, from, &reply));
     }
     fn update_product_info(&self, from: u64, name: String, quantity: Option<u128>, price: Option<u128>, error: Option<MarketError>) {
         let result = self.send(from, MarketAction::UpdateProductInfo { name: name.clone(), quantity, price });
@@ -36,7 +40,7 @@ impl TestFunc for Program<'_> {
         } else {
             Ok(MarketEvent::ProductInfoUpdated { name, quantity, price })
         };
-        assert!(result.contains(&(from, reply.encode())));
+        assert!(has_reply(&result, from, &reply));
     }
     fn delete_product(&self, from: u64, name: String, error: Option<MarketError>) {
         let result = self.send(from, MarketAction::DeleteProduct { name: name.clone() } );
@@ -46,17 +50,18 @@ impl TestFunc for Program<'_> {
         } else {
             Ok(MarketEvent::ProductDeleted { name })
         };
-        assert!(result.contains(&(from, reply.encode())));
+        assert!(has_reply(&result, from, &reply));
     }
     fn buy(&self, from: u64, value: u128, name: String, quantity: u128, delivery_address: String, error: Option<MarketError>) {
-        let result = self.send_with_value(from, MarketAction::Buy { name: name.clone(), quantity, delivery_address }, value);
+        let order_id = self.get_all_state().expect("Unexpected invalid state.").next_order_id;
+        let result = self.send_with_value(from, MarketAction::Buy { name: name.clone(), quantity, delivery_address: DeliveryAddress { country: String::new(), details: delivery_address }, contact: None, max_unit_price: None, exact: false, use_credit: false, redeem_points: 0, verbose: true }, value);
         assert!(!result.main_failed());
         let reply = if let Some(error) = error {
             Err(error)
         } else {
-            Ok(MarketEvent::Bought { buyer: from.into(), name: name.clone(), quantity })
+            Ok(MarketEvent::Bought { buyer: from.into(), name: name.clone(), quantity, order_id, tip: 0 })
         };
-        assert!(result.contains(&(from, reply.encode())));
+        assert!(has_reply(&result, from, &reply));
     }
     fn get_all_state(&self) -> Option<State> {
         let reply = self
@@ -71,6 +76,22 @@ impl TestFunc for Program<'_> {
 
 }
 
+/// Whether `result` holds a reply or notification to `to` carrying `event`, whatever its sequence number.
+pub fn has_event(result: &RunResult, to: u64, event: &MarketEvent) -> bool {
+    let is_event = |sequenced: &SequencedEvent| sequenced.event.encode() == event.encode();
+    result.log().iter().filter(|log| log.destination() == to.into()).any(|log| {
+        let replied = matches!(Result::<SequencedEvent, MarketError>::decode(&mut log.payload()), Ok(Ok(sequenced)) if is_event(&sequenced));
+        replied || SequencedEvent::decode(&mut log.payload()).is_ok_and(|sequenced| is_event(&sequenced))
+    })
+}
+
+pub fn has_reply(result: &RunResult, to: u64, reply: &Result<MarketEvent, MarketError>) -> bool {
+    match reply {
+        Ok(event) => has_event(result, to, event),
+        Err(error) => result.contains(&(to, Err::<MarketEvent, _>(error).encode())),
+    }
+}
+
 
 #[test]
 fn success_add_update_buy_delete_product() {
@@ -79,6 +100,7 @@ fn success_add_update_buy_delete_product() {
     let market = Program::current_opt(&system);
     let config = Config {
         public_key: "public key".to_string(),
+        ..Default::default()
     };
     let result = market.send(ADMIN, config);
     assert!(!result.main_failed());
@@ -136,6 +158,7 @@ fn failures_add_product() {
     let market = Program::current_opt(&system);
     let config = Config {
         public_key: "public key".to_string(),
+        ..Default::default()
     };
     let result = market.send(ADMIN, config);
     assert!(!result.main_failed());
@@ -154,6 +177,7 @@ fn failures_bought() {
     let market = Program::current_opt(&system);
     let config = Config {
         public_key: "public key".to_string(),
+        ..Default::default()
     };
     let result = market.send(ADMIN, config);
     assert!(!result.main_failed());
@@ -172,3 +196,3314 @@ fn failures_bought() {
 
 
 }
+
+#[test]
+fn state_size() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let reply: StateReply = market.read_state(StateQuery::GetStateSize).expect("Unexpected invalid state.");
+    let StateReply::StateSize { products, purchases } = reply else {
+        panic!("Unexpected reply to `GetStateSize`");
+    };
+    assert_eq!(products, 0);
+    assert_eq!(purchases, 0);
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 100, price, None);
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    let reply: StateReply = market.read_state(StateQuery::GetStateSize).expect("Unexpected invalid state.");
+    let StateReply::StateSize { products, purchases } = reply else {
+        panic!("Unexpected reply to `GetStateSize`");
+    };
+    assert!(products > 0);
+    assert!(purchases > 0);
+}
+
+#[test]
+fn case_sensitive_names() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 100, price, None);
+    market.add_product(ADMIN, "shoe".to_string(), 100, price, None);
+
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "SHOE".to_string(), 1, "delivery_address".to_string(), Some(MarketError::ThereIsNoSuchName));
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert_eq!(state.products.len(), 2);
+
+    let reply: StateReply = market.read_state(StateQuery::GetProductCount).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Count(2)));
+}
+
+#[test]
+fn case_insensitive_names() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        case_insensitive_names: true,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 100, price, None);
+    market.add_product(ADMIN, "shoe".to_string(), 100, price, Some(MarketError::AlreadyExists));
+
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "SHOE".to_string(), 1, "delivery_address".to_string(), None);
+    market.update_product_info(ADMIN, "sHoE".to_string(), Some(10), None, None);
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert_eq!(state.products.len(), 1);
+    assert_eq!(state.products[0].0, "shoe");
+    assert_eq!(state.products[0].1.display_name, "Shoe");
+    assert_eq!(state.products[0].1.quantity, 10);
+    assert_eq!(state.purchases[0].1[0].name, "shoe");
+}
+
+#[test]
+fn batched_payout() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 100, price, None);
+
+    let result = market.send(USERS[1], MarketAction::Payout { seller: ADMIN.into() });
+    assert!(result.contains(&(USERS[1], Err::<MarketEvent, _>(MarketError::NothingToPayout).encode())));
+
+    system.mint_to(USERS[0], 3*price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    market.buy(USERS[0], 2*price, "Product_#1".to_string(), 2, "delivery_address".to_string(), None);
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert_eq!(state.pending_payouts, vec![(ADMIN.into(), 3*price)]);
+    let reply: StateReply = market.read_state(StateQuery::GetSellerBalance(ADMIN.into())).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Balance(balance) if balance == 3*price));
+    let reply: StateReply = market.read_state(StateQuery::GetSellerBalance(USERS[1].into())).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Balance(0)));
+
+    // anyone can trigger the payout, but the value always goes to the seller
+    let result = market.send(USERS[1], MarketAction::Payout { seller: ADMIN.into() });
+    assert!(has_event(&result, USERS[1], &MarketEvent::PayoutSent { seller: ADMIN.into(), amount: 3*price }));
+    system.claim_value_from_mailbox(ADMIN);
+    assert_eq!(system.balance_of(ADMIN), 3*price);
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert!(state.pending_payouts.is_empty());
+}
+
+#[test]
+fn invalid_config() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: String::new(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(result.main_failed());
+
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let config = Config {
+        public_key: String::new(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, MarketAction::UpdateConfig { config });
+    assert!(!result.main_failed());
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidConfig).encode())));
+
+    let config = Config {
+        public_key: "public key".to_string(),
+        value_send_gas: u64::MAX,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, MarketAction::UpdateConfig { config });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidConfig).encode())));
+    let config = Config {
+        public_key: "public key".to_string(),
+        value_send_gas: 1_000_000_000,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, MarketAction::UpdateConfig { config: config.clone() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::ConfigUpdated { config }));
+}
+
+#[test]
+fn spend_cap() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let price = 10_000_000_000_000;
+    let config = Config {
+        public_key: "public key".to_string(),
+        max_spend_per_actor: Some(3*price),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    market.add_product(ADMIN, "Product_#1".to_string(), 100, price, None);
+
+    system.mint_to(USERS[0], 4*price);
+    market.buy(USERS[0], 2*price, "Product_#1".to_string(), 2, "delivery_address".to_string(), None);
+    market.buy(USERS[0], 2*price, "Product_#1".to_string(), 2, "delivery_address".to_string(), Some(MarketError::SpendCapExceeded));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), 2*price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    // the cap is per actor
+    system.mint_to(USERS[1], 3*price);
+    market.buy(USERS[1], 3*price, "Product_#1".to_string(), 3, "delivery_address".to_string(), None);
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    let spent = state.total_spent.iter().find(|(actor, _)| *actor == USERS[0].into()).map(|(_, spent)| *spent);
+    assert_eq!(spent, Some(3*price));
+
+    let reply: StateReply = market.read_state(StateQuery::GetActorContext(USERS[0].into())).expect("Unexpected invalid state.");
+    let StateReply::ActorContext(context) = reply else {
+        panic!("Unexpected reply to `GetActorContext`");
+    };
+    assert!(!context.is_admin);
+    assert_eq!(context.purchase_count, 2);
+    assert_eq!(context.total_spent, 3*price);
+
+    let reply: StateReply = market.read_state(StateQuery::GetActorContext(ADMIN.into())).expect("Unexpected invalid state.");
+    let StateReply::ActorContext(context) = reply else {
+        panic!("Unexpected reply to `GetActorContext`");
+    };
+    assert!(context.is_admin);
+    assert_eq!(context.purchase_count, 0);
+    assert_eq!(context.total_spent, 0);
+}
+
+#[test]
+fn bundles() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 10, price, None);
+    market.add_product(ADMIN, "Lace".to_string(), 3, price, None);
+
+    let components = vec![("Shoe".to_string(), 2), ("Lace".to_string(), 1)];
+    let result = market.send(USERS[0], MarketAction::AddBundle { name: "Pair".to_string(), components: components.clone(), price });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::AddBundle { name: "Pair".to_string(), components: vec![("Sock".to_string(), 1)], price });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::ThereIsNoSuchName).encode())));
+    let result = market.send(ADMIN, MarketAction::AddBundle { name: "Shoe".to_string(), components: components.clone(), price });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::AlreadyExists).encode())));
+    let result = market.send(ADMIN, MarketAction::AddBundle { name: "Pair".to_string(), components: components.clone(), price });
+    assert!(has_event(&result, ADMIN, &MarketEvent::BundleAdded { name: "Pair".to_string(), components, price }));
+
+    let reply: StateReply = market.read_state(StateQuery::QuoteBuy { name: "Pair".to_string(), quantity: 2 }).expect("Unexpected invalid state.");
+    let StateReply::Quote(Ok(quote)) = reply else {
+        panic!("Unexpected reply to `QuoteBuy`");
+    };
+    assert_eq!(quote.unit_price, price);
+    assert_eq!(quote.total, 2*price);
+    let reply: StateReply = market.read_state(StateQuery::QuoteBuy { name: "Pair".to_string(), quantity: 4 }).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Quote(Err(MarketError::BundleComponentUnavailable))));
+
+    system.mint_to(USERS[0], 4*price);
+    market.buy(USERS[0], 2*price, "Pair".to_string(), 2, "delivery_address".to_string(), None);
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    let quantity_of = |name: &str| state.products.iter().find(|(key, _)| key == name).map(|(_, product)| product.quantity);
+    assert_eq!(quantity_of("Shoe"), Some(6));
+    assert_eq!(quantity_of("Lace"), Some(1));
+    assert_eq!(state.purchases[0].1.len(), 1);
+    assert_eq!(state.purchases[0].1[0].name, "Pair");
+
+    // only one lace is left, so two bundles can't be assembled and nothing is taken from stock
+    market.buy(USERS[0], 2*price, "Pair".to_string(), 2, "delivery_address".to_string(), Some(MarketError::BundleComponentUnavailable));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), 2*price);
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    let quantity_of = |name: &str| state.products.iter().find(|(key, _)| key == name).map(|(_, product)| product.quantity);
+    assert_eq!(quantity_of("Shoe"), Some(6));
+    assert_eq!(quantity_of("Lace"), Some(1));
+}
+
+#[test]
+fn cancel_order() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 100, price, None);
+
+    system.mint_to(USERS[0], 4*price);
+    market.buy(USERS[0], 3*price, "Product_#1".to_string(), 2, "delivery_address".to_string(), None);
+    system.claim_value_from_mailbox(USERS[0]);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    assert_eq!(system.balance_of(USERS[0]), price);
+
+    // only the buyer can cancel their order
+    let result = market.send(USERS[1], MarketAction::CancelOrder { order_id: 0 });
+    assert!(result.contains(&(USERS[1], Err::<MarketEvent, _>(MarketError::ThereIsNoSuchOrder).encode())));
+
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 0 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderCancelled { order_id: 0, refund: 2*price, refund_percent: 10_000 }));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), 3*price);
+
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 0 });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::OrderNotCancellable).encode())));
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert_eq!(state.products[0].1.quantity, 99);
+    assert_eq!(state.pending_payouts, vec![(ADMIN.into(), price)]);
+    assert_eq!(state.total_spent, vec![(USERS[0].into(), price)]);
+
+    system.spend_blocks(11);
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 1 });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::RefundWindowClosed).encode())));
+}
+
+#[test]
+fn max_supply() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let result = market.send(ADMIN, MarketAction::AddProduct { name: "Edition".to_string(), quantity: 11, price, max_supply: Some(10), preorder: false, sku: String::new(), instant: false, delivery_payload: None });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::MaxSupplyExceeded).encode())));
+    let result = market.send(ADMIN, MarketAction::AddProduct { name: "Edition".to_string(), quantity: 5, price, max_supply: Some(10), preorder: false, sku: String::new(), instant: false, delivery_payload: None });
+    assert!(!result.main_failed());
+
+    let result = market.send(USERS[0], MarketAction::RestockProduct { name: "Edition".to_string(), quantity: 1 });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Edition".to_string(), quantity: 3 });
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductRestocked { name: "Edition".to_string(), quantity: 3 }));
+
+    // selling units doesn't give back supply
+    system.mint_to(USERS[0], 8*price);
+    market.buy(USERS[0], 8*price, "Edition".to_string(), 8, "delivery_address".to_string(), None);
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Edition".to_string(), quantity: 3 });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::MaxSupplyExceeded).encode())));
+    market.update_product_info(ADMIN, "Edition".to_string(), Some(3), None, Some(MarketError::MaxSupplyExceeded));
+    market.update_product_info(ADMIN, "Edition".to_string(), Some(2), None, None);
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert_eq!(state.products[0].1.quantity, 2);
+    assert_eq!(state.products[0].1.total_minted, 10);
+}
+
+#[test]
+fn log_unauthorized() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        log_unauthorized: true,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let result = market.send(USERS[0], MarketAction::DeleteProduct { name: "Product_#1".to_string() });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let notification = MarketEvent::UnauthorizedAttempt { who: USERS[0].into(), action: "DeleteProduct".to_string() };
+    assert!(has_event(&result, ADMIN, &notification));
+
+    market.add_product(ADMIN, "Product_#1".to_string(), 100, price, None);
+    let result = market.send(ADMIN, MarketAction::UpdateConfig { config: Config { public_key: "public key".to_string(), ..Default::default() } });
+    assert!(!result.main_failed());
+
+    let result = market.send(USERS[0], MarketAction::DeleteProduct { name: "Product_#1".to_string() });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    assert!(!has_event(&result, ADMIN, &notification));
+}
+
+#[test]
+fn stock_history() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        max_history_len: 3,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], 2*price, "Product_#1".to_string(), 2, "delivery_address".to_string(), None);
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Product_#1".to_string(), quantity: 5 });
+    assert!(!result.main_failed());
+
+    let history = |market: &Program| {
+        let reply: StateReply = market.read_state(StateQuery::GetStockHistory("Product_#1".to_string())).expect("Unexpected invalid state.");
+        let StateReply::StockHistory(history) = reply else {
+            panic!("Unexpected reply to `GetStockHistory`");
+        };
+        history.into_iter().map(|(_, delta)| delta).collect::<Vec<_>>()
+    };
+    assert_eq!(history(&market), vec![10, -2, 5]);
+
+    // the oldest movement is evicted
+    market.update_product_info(ADMIN, "Product_#1".to_string(), Some(4), None, None);
+    assert_eq!(history(&market), vec![-2, 5, -9]);
+}
+
+#[test]
+fn compare_and_update_product() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    let update = |expected_quantity| MarketAction::CompareAndUpdateProduct {
+        name: "Product_#1".to_string(),
+        expected_quantity,
+        expected_price: price,
+        new_quantity: 20,
+        new_price: 2*price,
+    };
+    // the sale happened after the admin read the quantity
+    let result = market.send(ADMIN, update(10));
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::ConcurrentModification).encode())));
+    let result = market.send(ADMIN, update(9));
+    let event = MarketEvent::ProductInfoUpdated { name: "Product_#1".to_string(), quantity: Some(20), price: Some(2*price) };
+    assert!(has_event(&result, ADMIN, &event));
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert_eq!(state.products[0].1.quantity, 20);
+    assert_eq!(state.products[0].1.price, 2*price);
+
+    // each update that went through is logged once, under its own name
+    let reply: StateReply = market.read_state(StateQuery::GetAdminActions { limit: 10 }).expect("Unexpected invalid state.");
+    let StateReply::AdminActions(actions) = reply else {
+        panic!("Unexpected reply to `GetAdminActions`");
+    };
+    let actions: Vec<_> = actions.into_iter().map(|(_, _, action)| action).collect();
+    assert_eq!(actions, vec!["AddProduct".to_string(), "CompareAndUpdateProduct".to_string()]);
+}
+
+#[test]
+fn overpayment_as_tip() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_overpayment: false,
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+
+    system.mint_to(USERS[0], 3*price);
+    let result = market.send_with_value(USERS[0], MarketAction::Buy { name: "Product_#1".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: false, use_credit: false, redeem_points: 0, verbose: true }, 3*price);
+    let event = MarketEvent::Bought { buyer: USERS[0].into(), name: "Product_#1".to_string(), quantity: 1, order_id: 0, tip: 2*price };
+    assert!(has_event(&result, USERS[0], &event));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), 0);
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert_eq!(state.pending_payouts, vec![(ADMIN.into(), 3*price)]);
+
+    // the tip stays with the seller when the order is cancelled
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 0 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderCancelled { order_id: 0, refund: price, refund_percent: 10_000 }));
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert_eq!(state.pending_payouts, vec![(ADMIN.into(), 2*price)]);
+
+    // a non-verbose buy only reports the order id
+    system.mint_to(USERS[0], price);
+    let result = market.send_with_value(USERS[0], MarketAction::Buy { name: "Product_#1".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: false, use_credit: false, redeem_points: 0, verbose: false }, price);
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderPlaced { order_id: 1 }));
+}
+
+#[test]
+fn inventory_oracle() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        inventory_oracle: Some(USERS[2].into()),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let result = market.send(ADMIN, MarketAction::AddProduct { name: "Product_#1".to_string(), quantity: 10, price, max_supply: None, preorder: false, sku: String::new(), instant: false, delivery_payload: None });
+    assert!(result.contains(&(USERS[2], InventoryUpdate { name: "Product_#1".to_string(), quantity: 10 }.encode())));
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Product_#1".to_string(), quantity: 5 });
+    assert!(result.contains(&(USERS[2], InventoryUpdate { name: "Product_#1".to_string(), quantity: 15 }.encode())));
+}
+
+#[test]
+fn oracle_confirms_stock() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        inventory_oracle: Some(USERS[2].into()),
+        confirm_stock_with_oracle: true,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+    // there's no confirming without an oracle
+    let result = market.send(ADMIN, MarketAction::UpdateConfig { config: Config { public_key: "public key".to_string(), confirm_stock_with_oracle: true, ..Default::default() } });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidConfig).encode())));
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 4*price);
+    let buy = || MarketAction::Buy { name: "Product_#1".to_string(), quantity: 2, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: false, use_credit: false, redeem_points: 0, verbose: true };
+    let query = InventoryQuery { name: "Product_#1".to_string() };
+
+    // the purchase waits for the oracle's count
+    let result = market.send_with_value(USERS[0], buy(), 2*price);
+    assert!(result.contains(&(USERS[2], query.encode())));
+    // the waiting value is accounted for and can't be swept
+    let reply: StateReply = market.read_state(StateQuery::GetBalanceSheet).expect("Unexpected invalid state.");
+    let StateReply::BalanceSheet(sheet) = reply else {
+        panic!("Unexpected reply to `GetBalanceSheet`");
+    };
+    assert_eq!(sheet.pending_buys, 2*price);
+    assert_eq!(sheet.unaccounted, sheet.contract_balance.saturating_sub(2*price));
+    let result = market.send(ADMIN, MarketAction::SweepUnaccountedFunds { to: ADMIN.into() });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::BalanceBelowObligations).encode())) || result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::NothingToSweep).encode())));
+    let mailbox = system.get_mailbox(USERS[2]);
+    let result = mailbox.reply(Log::builder().source(market.id()).dest(USERS[2]).payload(query), 2u128, 0).expect("Unexpected missing oracle query.");
+    assert!(has_event(&result, USERS[0], &MarketEvent::Bought { buyer: USERS[0].into(), name: "Product_#1".to_string(), quantity: 2, order_id: 0, tip: 0 }));
+
+    let result = market.send_with_value(USERS[0], buy(), 2*price);
+    assert!(!result.main_failed());
+    let query = InventoryQuery { name: "Product_#1".to_string() };
+    let result = mailbox.reply(Log::builder().source(market.id()).dest(USERS[2]).payload(query), 1u128, 0).expect("Unexpected missing oracle query.");
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::OracleStockMismatch).encode())));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 8);
+}
+
+#[test]
+fn categories() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    for name in ["Shoe", "Boot", "Hat", "Scarf"] {
+        market.add_product(ADMIN, name.to_string(), 10, price, None);
+    }
+    for (name, category) in [("Shoe", "Footwear"), ("Boot", "Footwear"), ("Hat", "Accessories")] {
+        let result = market.send(ADMIN, MarketAction::SetProductCategory { name: name.to_string(), category: Some(category.to_string()) });
+        assert!(!result.main_failed());
+    }
+    let result = market.send(ADMIN, MarketAction::SetProductCategory { name: "Scarf".to_string(), category: Some(String::new()) });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidCategory).encode())));
+    let result = market.send(USERS[0], MarketAction::SetProductCategory { name: "Scarf".to_string(), category: None });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+
+    let reply: StateReply = market.read_state(StateQuery::GetCategories).expect("Unexpected invalid state.");
+    let StateReply::Categories(categories) = reply else {
+        panic!("Unexpected reply to `GetCategories`");
+    };
+    assert_eq!(categories, vec!["Accessories".to_string(), "Footwear".to_string()]);
+}
+
+#[test]
+fn forget_history() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 3*price);
+    for _ in 0..3 {
+        market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    }
+
+    // order 0 is delivered, order 1 cancelled and order 2 still on its way
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 0, status: Status::Delivered, tracking_number: None });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::IllegalStatusTransition).encode())));
+    for (order_id, status) in [(0, Status::Shipped), (0, Status::Delivered), (2, Status::Shipped)] {
+        let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id, status: status.clone(), tracking_number: None });
+        assert!(has_event(&result, ADMIN, &MarketEvent::PurchaseStatusUpdated { order_id, status }));
+    }
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 1 });
+    assert!(!result.main_failed());
+
+    let result = market.send(USERS[0], MarketAction::ForgetMyHistory);
+    assert!(has_event(&result, USERS[0], &MarketEvent::HistoryForgotten { removed_count: 2 }));
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert_eq!(state.purchases[0].1.len(), 1);
+    assert_eq!(state.purchases[0].1[0].order_id, 2);
+    // spend is kept for the market's own accounting
+    assert_eq!(state.total_spent, vec![(USERS[0].into(), 2*price)]);
+
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 0, status: Status::Delivered, tracking_number: None });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::ThereIsNoSuchOrder).encode())));
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 2, status: Status::Delivered, tracking_number: None });
+    assert!(!result.main_failed());
+}
+
+#[test]
+fn preorders() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let result = market.send(ADMIN, MarketAction::AddProduct { name: "Console".to_string(), quantity: 0, price, max_supply: None, preorder: true, sku: String::new(), instant: false, delivery_payload: None });
+    assert!(!result.main_failed());
+
+    system.mint_to(USERS[0], 2*price);
+    system.mint_to(USERS[1], 2*price);
+    system.mint_to(USERS[2], price);
+    market.buy(USERS[0], 2*price, "Console".to_string(), 2, "delivery_address".to_string(), None);
+    market.buy(USERS[1], 2*price, "Console".to_string(), 2, "delivery_address".to_string(), None);
+    market.buy(USERS[2], price, "Console".to_string(), 1, "delivery_address".to_string(), None);
+    let result = market.send(USERS[1], MarketAction::CancelOrder { order_id: 1 });
+    assert!(has_event(&result, USERS[1], &MarketEvent::OrderCancelled { order_id: 1, refund: 2*price, refund_percent: 10_000 }));
+
+    let status_of = |market: &Program, order_id: u128| {
+        let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+        state.purchases.into_iter().flat_map(|(_, purchases)| purchases).find(|purchase| purchase.order_id == order_id).map(|purchase| purchase.status)
+    };
+    assert!(matches!(status_of(&market, 0), Some(Status::Preordered)));
+
+    // one unit isn't enough for the oldest preorder, so the later one has to wait as well
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Console".to_string(), quantity: 1 });
+    assert!(!result.main_failed());
+    assert!(matches!(status_of(&market, 0), Some(Status::Preordered)));
+    assert!(matches!(status_of(&market, 2), Some(Status::Preordered)));
+
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Console".to_string(), quantity: 3 });
+    assert!(!result.main_failed());
+    assert!(matches!(status_of(&market, 0), Some(Status::PaidFor)));
+    assert!(matches!(status_of(&market, 1), Some(Status::Cancelled)));
+    assert!(matches!(status_of(&market, 2), Some(Status::PaidFor)));
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert_eq!(state.products[0].1.quantity, 1);
+}
+
+#[test]
+fn freeze_product() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    let result = market.send(USERS[0], MarketAction::FreezeProduct { name: "Product_#1".to_string(), frozen: true });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::FreezeProduct { name: "Product_#1".to_string(), frozen: true });
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductFreezeUpdated { name: "Product_#1".to_string(), frozen: true }));
+
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), Some(MarketError::ProductFrozen));
+    system.claim_value_from_mailbox(USERS[0]);
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 0 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderCancelled { order_id: 0, refund: price, refund_percent: 10_000 }));
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert!(state.products[0].1.frozen);
+
+    let result = market.send(ADMIN, MarketAction::FreezeProduct { name: "Product_#1".to_string(), frozen: false });
+    assert!(!result.main_failed());
+    system.claim_value_from_mailbox(USERS[0]);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+}
+
+#[test]
+fn products_modified_since() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    for name in ["Shoe", "Boot", "Hat", "Scarf"] {
+        market.add_product(ADMIN, name.to_string(), 10, price, None);
+    }
+    system.spend_blocks(5);
+    let since = u64::from(system.block_height());
+
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Shoe".to_string(), 1, "delivery_address".to_string(), None);
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Boot".to_string(), quantity: 1 });
+    assert!(!result.main_failed());
+    market.delete_product(ADMIN, "Hat".to_string(), None);
+
+    let reply: StateReply = market.read_state(StateQuery::GetProductsModifiedSince(since)).expect("Unexpected invalid state.");
+    let StateReply::ProductsModifiedSince { products, deleted } = reply else {
+        panic!("Unexpected reply to `GetProductsModifiedSince`");
+    };
+    let mut names: Vec<String> = products.into_iter().map(|(name, _)| name).collect();
+    names.sort();
+    assert_eq!(names, vec!["Boot".to_string(), "Shoe".to_string()]);
+    assert_eq!(deleted, vec!["Hat".to_string()]);
+}
+
+#[test]
+fn sku() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let add = |name: &str, sku: &str| MarketAction::AddProduct { name: name.to_string(), quantity: 10, price, max_supply: None, preorder: false, sku: sku.to_string(), instant: false, delivery_payload: None };
+    let result = market.send(ADMIN, add("Shoe", "SKU-1"));
+    assert!(!result.main_failed());
+    let result = market.send(ADMIN, add("Boot", "SKU-1"));
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::DuplicateSku).encode())));
+    // products without a SKU don't clash
+    let result = market.send(ADMIN, add("Hat", ""));
+    assert!(!result.main_failed());
+    let result = market.send(ADMIN, add("Scarf", ""));
+    assert!(!result.main_failed());
+
+    let reply: StateReply = market.read_state(StateQuery::GetProductBySku("SKU-1".to_string())).expect("Unexpected invalid state.");
+    let StateReply::Product(Some((name, product))) = reply else {
+        panic!("Unexpected reply to `GetProductBySku`");
+    };
+    assert_eq!(name, "Shoe");
+    assert_eq!(product.sku, "SKU-1");
+
+    // deleting the product frees its SKU
+    market.delete_product(ADMIN, "Shoe".to_string(), None);
+    let reply: StateReply = market.read_state(StateQuery::GetProductBySku("SKU-1".to_string())).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Product(None)));
+    let result = market.send(ADMIN, add("Boot", "SKU-1"));
+    assert!(!result.main_failed());
+}
+
+#[test]
+fn delete_products_batch() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    for name in ["Shoe", "Boot", "Hat"] {
+        market.add_product(ADMIN, name.to_string(), 10, price, None);
+    }
+
+    let names = vec!["Shoe".to_string(), "Scarf".to_string(), "Hat".to_string()];
+    let result = market.send(USERS[0], MarketAction::DeleteProductsBatch(names.clone()));
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::DeleteProductsBatch(names));
+    let event = MarketEvent::ProductsBatchDeleted { deleted: vec!["Shoe".to_string(), "Hat".to_string()], not_found: vec!["Scarf".to_string()] };
+    assert!(has_event(&result, ADMIN, &event));
+
+    let state: State = market.get_all_state().expect("Unexpected invalid game state.");
+    assert_eq!(state.products.len(), 1);
+    assert_eq!(state.products[0].0, "Boot");
+}
+
+#[test]
+fn purchases_for_actors() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        max_query_batch: 2,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    for user in USERS {
+        system.mint_to(user, price);
+        market.buy(user, price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    }
+
+    let reply: StateReply = market.read_state(StateQuery::GetPurchasesForActors(vec![USERS[0].into(), USERS[2].into()])).expect("Unexpected invalid state.");
+    let StateReply::Purchases { purchases, truncated: false } = reply else {
+        panic!("Unexpected reply to `GetPurchasesForActors`");
+    };
+    let buyers: Vec<gstd::ActorId> = purchases.iter().map(|(buyer, _)| *buyer).collect();
+    assert_eq!(buyers, vec![USERS[0].into(), USERS[2].into()]);
+
+    // actors past the cap are left out
+    let actors = USERS.iter().map(|user| (*user).into()).collect();
+    let reply: StateReply = market.read_state(StateQuery::GetPurchasesForActors(actors)).expect("Unexpected invalid state.");
+    let StateReply::Purchases { purchases, truncated: true } = reply else {
+        panic!("Unexpected reply to `GetPurchasesForActors`");
+    };
+    let buyers: Vec<gstd::ActorId> = purchases.iter().map(|(buyer, _)| *buyer).collect();
+    assert_eq!(buyers, vec![USERS[0].into(), USERS[1].into()]);
+}
+
+#[test]
+fn margin_report() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config.clone());
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let cost = 4_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    market.add_product(ADMIN, "Product_#2".to_string(), 10, price, None);
+    let result = market.send(USERS[0], MarketAction::SetProductCost { name: "Product_#1".to_string(), cost: Some(cost) });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::SetProductCost { name: "Product_#1".to_string(), cost: Some(cost) });
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductCostSet { name: "Product_#1".to_string(), cost: Some(cost) }));
+
+    system.mint_to(USERS[0], 4*price);
+    market.buy(USERS[0], 2*price, "Product_#1".to_string(), 2, "delivery_address".to_string(), None);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    // Products without a known cost are left out of the report.
+    market.buy(USERS[0], price, "Product_#2".to_string(), 1, "delivery_address".to_string(), None);
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 1 });
+    assert!(!result.main_failed());
+
+    let reply: StateReply = market.read_state(StateQuery::GetMarginReport).expect("Unexpected invalid state.");
+    let StateReply::MarginReport(Some(report)) = reply else {
+        panic!("Unexpected reply to `GetMarginReport`");
+    };
+    assert_eq!((report.revenue, report.cost, report.margin), (2*price, 2*cost, 2*(price - cost) as i128));
+
+    let result = market.send(ADMIN, MarketAction::UpdateConfig { config: Config { hide_cost: true, ..config } });
+    assert!(!result.main_failed());
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(state.products.iter().all(|(_, product_data)| product_data.cost.is_none()));
+    let reply: StateReply = market.read_state(StateQuery::GetMarginReport).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::MarginReport(Some(report)) if report.cost == 2*cost));
+}
+
+#[test]
+fn flash_sale() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let sale_price = 5_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    let result = market.send(USERS[0], MarketAction::StartFlashSale { name: "Product_#1".to_string(), sale_price, duration_blocks: 5 });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::StartFlashSale { name: "Product_#1".to_string(), sale_price, duration_blocks: 0 });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidFlashSale).encode())));
+    for sale_price in [price, price + 1] {
+        let result = market.send(ADMIN, MarketAction::StartFlashSale { name: "Product_#1".to_string(), sale_price, duration_blocks: 5 });
+        assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidFlashSale).encode())));
+    }
+    let result = market.send(ADMIN, MarketAction::StartFlashSale { name: "Product_#1".to_string(), sale_price, duration_blocks: 5 });
+    assert!(!result.main_failed());
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    let flash_sale = state.products[0].1.flash_sale.clone().expect("Unexpected missing flash sale.");
+    assert!(has_event(&result, ADMIN, &MarketEvent::FlashSaleStarted { name: "Product_#1".to_string(), sale_price, ends_at: flash_sale.ends_at }));
+    let result = market.send(ADMIN, MarketAction::StartFlashSale { name: "Product_#1".to_string(), sale_price, duration_blocks: 5 });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::FlashSaleActive).encode())));
+
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], sale_price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    system.spend_blocks(5);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(state.products[0].1.flash_sale.is_none());
+    assert_eq!(state.products[0].1.price, price);
+    market.buy(USERS[0], sale_price, "Product_#1".to_string(), 1, "delivery_address".to_string(), Some(MarketError::InsufficientValue));
+    system.claim_value_from_mailbox(USERS[0]);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+}
+
+#[test]
+fn buy_receipts() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        buy_receipts: true,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 3*price);
+    let result = market.send_with_value(USERS[0], MarketAction::Buy { name: "Product_#1".to_string(), quantity: 2, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: false, use_credit: false, redeem_points: 0, verbose: false }, 3*price);
+    assert!(!result.main_failed());
+    let receipt = result.log().iter().filter(|log| log.destination() == USERS[0].into()).find_map(|log| match Result::<SequencedEvent, MarketError>::decode(&mut log.payload()) {
+        Ok(Ok(SequencedEvent { event: MarketEvent::Receipt(receipt), .. })) => Some(receipt),
+        _ => None,
+    }).expect("Unexpected missing receipt.");
+    assert_eq!((receipt.order_id, receipt.buyer, receipt.name.as_str()), (0, USERS[0].into(), "Product_#1"));
+    assert_eq!((receipt.unit_price, receipt.quantity, receipt.total_paid, receipt.change, receipt.tip), (price, 2, 2*price, price, 0));
+    assert_ne!(receipt.hash, [0; 32]);
+}
+
+#[test]
+fn products_sorted_by_key() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    for name in ["Shoe", "Boot", "Scarf", "Hat", "Belt"] {
+        market.add_product(ADMIN, name.to_string(), 10, price, None);
+    }
+
+    let reply: StateReply = market.read_state(StateQuery::GetProducts).expect("Unexpected invalid state.");
+    let StateReply::Products { products, truncated: false } = reply else {
+        panic!("Unexpected reply to `GetProducts`");
+    };
+    let names: Vec<&str> = products.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, vec!["Belt", "Boot", "Hat", "Scarf", "Shoe"]);
+    assert!(products.binary_search_by(|(name, _)| name.as_str().cmp("Hat")).is_ok());
+}
+
+#[test]
+fn revalidate_prices() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+
+    let result = market.send(USERS[0], MarketAction::RevalidatePrices);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::RevalidatePrices);
+    assert!(has_event(&result, ADMIN, &MarketEvent::PricesNeedingAttention(vec![])));
+}
+
+#[test]
+fn product_translations() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 10, price, None);
+    let result = market.send(USERS[0], MarketAction::SetProductTranslation { name: "Shoe".to_string(), lang: "de".to_string(), translation: "Schuh".to_string() });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::SetProductTranslation { name: "Shoe".to_string(), lang: String::new(), translation: "Schuh".to_string() });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidTranslation).encode())));
+    let result = market.send(ADMIN, MarketAction::SetProductTranslation { name: "Shoe".to_string(), lang: "de".to_string(), translation: "Schuh".to_string() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductTranslationSet { name: "Shoe".to_string(), lang: "de".to_string(), translation: "Schuh".to_string() }));
+
+    let display_name = |lang: Option<&str>| {
+        let reply: StateReply = market.read_state(StateQuery::GetProduct { name: "Shoe".to_string(), lang: lang.map(str::to_string) }).expect("Unexpected invalid state.");
+        let StateReply::Product(Some((_, product_data))) = reply else {
+            panic!("Unexpected reply to `GetProduct`");
+        };
+        product_data.display_name
+    };
+    assert_eq!(display_name(Some("de")), "Schuh");
+    assert_eq!(display_name(Some("fr")), "Shoe");
+    assert_eq!(display_name(None), "Shoe");
+
+    let result = market.send(ADMIN, MarketAction::SetProductTranslation { name: "Shoe".to_string(), lang: "de".to_string(), translation: String::new() });
+    assert!(!result.main_failed());
+    assert_eq!(display_name(Some("de")), "Shoe");
+}
+
+#[test]
+fn paginated_queries() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    for name in ["Shoe", "Boot", "Scarf", "Hat", "Belt"] {
+        market.add_product(ADMIN, name.to_string(), 10, price, None);
+    }
+    for user in USERS {
+        system.mint_to(user, price);
+        market.buy(user, price, "Hat".to_string(), 1, "delivery_address".to_string(), None);
+    }
+
+    let products_page = |offset, limit| {
+        let reply: StateReply = market.read_state(StateQuery::GetProductsPage { offset, limit }).expect("Unexpected invalid state.");
+        let StateReply::Products { products, truncated } = reply else {
+            panic!("Unexpected reply to `GetProductsPage`");
+        };
+        (products.into_iter().map(|(name, _)| name).collect::<Vec<_>>(), truncated)
+    };
+    assert_eq!(products_page(0, 2), (vec!["Belt".to_string(), "Boot".to_string()], true));
+    assert_eq!(products_page(2, 2), (vec!["Hat".to_string(), "Scarf".to_string()], true));
+    assert_eq!(products_page(4, 2), (vec!["Shoe".to_string()], false));
+    assert_eq!(products_page(10, 2), (vec![], false));
+
+    let reply: StateReply = market.read_state(StateQuery::GetPurchasesPage { offset: 1, limit: 5 }).expect("Unexpected invalid state.");
+    let StateReply::Purchases { purchases, truncated: false } = reply else {
+        panic!("Unexpected reply to `GetPurchasesPage`");
+    };
+    let buyers: Vec<gstd::ActorId> = purchases.iter().map(|(buyer, _)| *buyer).collect();
+    assert_eq!(buyers, vec![USERS[1].into(), USERS[2].into()]);
+
+    let reply: StateReply = market.read_state(StateQuery::GetProducts).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Products { products, truncated: false } if products.len() == 5));
+}
+
+#[test]
+fn restock_all() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Boot".to_string(), 3, price, None);
+    let result = market.send(ADMIN, MarketAction::AddProduct { name: "Shoe".to_string(), quantity: 8, price, max_supply: Some(12), preorder: false, sku: String::new(), instant: false, delivery_payload: None });
+    assert!(!result.main_failed());
+
+    let result = market.send(USERS[0], MarketAction::RestockAll { quantity: 10, delta: false });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::RestockAll { quantity: 10, delta: false });
+    assert!(has_event(&result, ADMIN, &MarketEvent::AllRestocked { product_count: 2 }));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(state.products.iter().all(|(_, product_data)| product_data.quantity == 10));
+
+    // "Shoe" can only take 2 more units, so nothing is restocked
+    let result = market.send(ADMIN, MarketAction::RestockAll { quantity: 3, delta: true });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::MaxSupplyExceeded).encode())));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(state.products.iter().all(|(_, product_data)| product_data.quantity == 10));
+
+    let result = market.send(ADMIN, MarketAction::RestockAll { quantity: 2, delta: true });
+    assert!(has_event(&result, ADMIN, &MarketEvent::AllRestocked { product_count: 2 }));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(state.products.iter().all(|(_, product_data)| product_data.quantity == 12));
+}
+
+#[test]
+fn allowed_countries() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 10, price, None);
+    let countries = Some(vec!["DE".to_string(), "FR".to_string()]);
+    let result = market.send(USERS[0], MarketAction::SetAllowedCountries { name: "Shoe".to_string(), countries: countries.clone() });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::SetAllowedCountries { name: "Shoe".to_string(), countries: countries.clone() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::AllowedCountriesSet { name: "Shoe".to_string(), countries }));
+
+    system.mint_to(USERS[0], 2*price);
+    let buy = |country: &str| MarketAction::Buy { name: "Shoe".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: country.to_string(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: false, use_credit: false, redeem_points: 0, verbose: false };
+    let result = market.send_with_value(USERS[0], buy("US"), price);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotDeliverableToRegion).encode())));
+    system.claim_value_from_mailbox(USERS[0]);
+    let result = market.send_with_value(USERS[0], buy("fr"), price);
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderPlaced { order_id: 0 }));
+
+    let result = market.send(ADMIN, MarketAction::SetAllowedCountries { name: "Shoe".to_string(), countries: Some(vec![]) });
+    assert!(!result.main_failed());
+    let result = market.send_with_value(USERS[0], buy("US"), price);
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderPlaced { order_id: 1 }));
+}
+
+#[test]
+fn event_seq() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        log_unauthorized: true,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let result = market.send(ADMIN, MarketAction::AddProduct { name: "Product_#1".to_string(), quantity: 10, price, max_supply: None, preorder: false, sku: String::new(), instant: false, delivery_payload: None });
+    let event = MarketEvent::ProductAdded { name: "Product_#1".to_string(), quantity: 10, price, max_supply: None, preorder: false, sku: String::new(), instant: false };
+    assert!(result.contains(&(ADMIN, Ok::<_, MarketError>(SequencedEvent { seq: 1, event, gas_used: None }).encode())));
+
+    // errors aren't events, but the notification sent along is
+    let result = market.send(USERS[0], MarketAction::DeleteProduct { name: "Product_#1".to_string() });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let notification = MarketEvent::UnauthorizedAttempt { who: USERS[0].into(), action: "DeleteProduct".to_string() };
+    assert!(result.contains(&(ADMIN, SequencedEvent { seq: 2, event: notification, gas_used: None }.encode())));
+    market.delete_product(ADMIN, "Product_#2".to_string(), Some(MarketError::ThereIsNoSuchName));
+
+    let reply: StateReply = market.read_state(StateQuery::GetEventSeq).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::EventSeq(2)));
+}
+
+#[test]
+fn stock_never_goes_negative() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let result = market.send(ADMIN, MarketAction::AddProduct { name: "Shoe".to_string(), quantity: 2, price, max_supply: None, preorder: true, sku: String::new(), instant: false, delivery_payload: None });
+    assert!(!result.main_failed());
+    let result = market.send(ADMIN, MarketAction::AddBundle { name: "Pair".to_string(), components: vec![("Shoe".to_string(), 2)], price });
+    assert!(!result.main_failed());
+
+    // preorders beyond the stock leave it untouched
+    system.mint_to(USERS[0], 8*price);
+    market.buy(USERS[0], 3*price, "Shoe".to_string(), 3, "delivery_address".to_string(), None);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 2);
+
+    // bundles only sell from stock, even when a component takes preorders
+    market.buy(USERS[0], 2*price, "Pair".to_string(), 2, "delivery_address".to_string(), Some(MarketError::BundleComponentUnavailable));
+    system.claim_value_from_mailbox(USERS[0]);
+    market.buy(USERS[0], price, "Pair".to_string(), 1, "delivery_address".to_string(), None);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 0);
+
+    // the queued preorder keeps later orders waiting rather than overselling
+    market.buy(USERS[0], price, "Shoe".to_string(), 1, "delivery_address".to_string(), None);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 0);
+    assert_eq!(state.purchases[0].1.iter().filter(|purchase| matches!(purchase.status, Status::Preordered)).count(), 2);
+}
+
+#[test]
+fn transfer_purchase() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    let result = market.send(USERS[1], MarketAction::TransferPurchase { order_id: 0, to: USERS[1].into() });
+    assert!(result.contains(&(USERS[1], Err::<MarketEvent, _>(MarketError::ThereIsNoSuchOrder).encode())));
+    let result = market.send(USERS[0], MarketAction::TransferPurchase { order_id: 0, to: USERS[1].into() });
+    assert!(has_event(&result, USERS[0], &MarketEvent::PurchaseTransferred { order_id: 0, from: USERS[0].into(), to: USERS[1].into() }));
+    let spent = |state: &State, user: u64| state.total_spent.iter().find(|(actor, _)| *actor == user.into()).map_or(0, |(_, spent)| *spent);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!((spent(&state, USERS[0]), spent(&state, USERS[1])), (price, price));
+
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 1, status: Status::Shipped, tracking_number: None });
+    assert!(!result.main_failed());
+    let result = market.send(USERS[0], MarketAction::TransferPurchase { order_id: 1, to: USERS[1].into() });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::OrderNotTransferable).encode())));
+
+    // the order now belongs to the recipient, who gets the refund
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 0 });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::ThereIsNoSuchOrder).encode())));
+    let result = market.send(USERS[1], MarketAction::CancelOrder { order_id: 0 });
+    assert!(has_event(&result, USERS[1], &MarketEvent::OrderCancelled { order_id: 0, refund: price, refund_percent: 10_000 }));
+    system.claim_value_from_mailbox(USERS[1]);
+    assert_eq!(system.balance_of(USERS[1]), price);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!((spent(&state, USERS[0]), spent(&state, USERS[1])), (price, 0));
+}
+
+#[test]
+fn auto_delist_on_zero() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        auto_delist_on_zero: true,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 2, price, None);
+    market.add_product(ADMIN, "Boot".to_string(), 2, price, None);
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], price, "Shoe".to_string(), 1, "delivery_address".to_string(), None);
+    let result = market.send_with_value(USERS[0], MarketAction::Buy { name: "Shoe".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: false, use_credit: false, redeem_points: 0, verbose: false }, price);
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductAutoDelisted { name: "Shoe".to_string() }));
+
+    let listed = || {
+        let reply: StateReply = market.read_state(StateQuery::GetProducts).expect("Unexpected invalid state.");
+        let StateReply::Products { products, .. } = reply else {
+            panic!("Unexpected reply to `GetProducts`");
+        };
+        products.into_iter().map(|(name, _)| name).collect::<Vec<_>>()
+    };
+    assert_eq!(listed(), vec!["Boot".to_string()]);
+
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Shoe".to_string(), quantity: 1 });
+    assert!(!result.main_failed());
+    assert_eq!(listed(), vec!["Boot".to_string(), "Shoe".to_string()]);
+}
+
+#[test]
+fn max_batch_size() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        max_batch_size: 2,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    for name in ["Shoe", "Boot", "Hat"] {
+        market.add_product(ADMIN, name.to_string(), 10, price, None);
+    }
+
+    let result = market.send(ADMIN, MarketAction::DeleteProductsBatch(vec!["Shoe".to_string(), "Boot".to_string(), "Hat".to_string()]));
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::BatchTooLarge).encode())));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products.len(), 3);
+
+    let result = market.send(ADMIN, MarketAction::DeleteProductsBatch(vec!["Shoe".to_string(), "Boot".to_string()]));
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductsBatchDeleted { deleted: vec!["Shoe".to_string(), "Boot".to_string()], not_found: vec![] }));
+}
+
+#[test]
+fn max_unit_price() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    market.update_product_info(ADMIN, "Product_#1".to_string(), None, Some(2*price), None);
+
+    system.mint_to(USERS[0], 2*price);
+    let buy = |max_unit_price| MarketAction::Buy { name: "Product_#1".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price, exact: false, use_credit: false, redeem_points: 0, verbose: false };
+    let result = market.send_with_value(USERS[0], buy(Some(price)), 2*price);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::PriceAboveMax).encode())));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), 2*price);
+
+    let result = market.send_with_value(USERS[0], buy(Some(2*price)), 2*price);
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderPlaced { order_id: 0 }));
+}
+
+#[test]
+fn inventory_snapshots() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        snapshot_interval_blocks: 5,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    system.spend_blocks(5);
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Product_#1".to_string(), quantity: 3 });
+    assert!(!result.main_failed());
+
+    let snapshots = |limit| {
+        let reply: StateReply = market.read_state(StateQuery::GetInventorySnapshots { limit }).expect("Unexpected invalid state.");
+        let StateReply::InventorySnapshots(snapshots) = reply else {
+            panic!("Unexpected reply to `GetInventorySnapshots`");
+        };
+        snapshots.into_iter().map(|(_, stock)| stock).collect::<Vec<_>>()
+    };
+    assert_eq!(snapshots(10), vec![9, 11]);
+    assert_eq!(snapshots(1), vec![11]);
+}
+
+#[test]
+fn purchase_cooldown() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    let result = market.send(USERS[0], MarketAction::SetPurchaseCooldown { name: "Product_#1".to_string(), cooldown_blocks: Some(5) });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::SetPurchaseCooldown { name: "Product_#1".to_string(), cooldown_blocks: Some(5) });
+    assert!(has_event(&result, ADMIN, &MarketEvent::PurchaseCooldownSet { name: "Product_#1".to_string(), cooldown_blocks: Some(5) }));
+
+    system.mint_to(USERS[0], 2*price);
+    system.mint_to(USERS[1], price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), Some(MarketError::PurchaseCooldownActive));
+    system.claim_value_from_mailbox(USERS[0]);
+    // the cooldown is per buyer
+    market.buy(USERS[1], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    system.spend_blocks(5);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+}
+
+#[test]
+fn orphaned_purchases() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 10, price, None);
+    market.add_product(ADMIN, "Boot".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 2*price);
+    system.mint_to(USERS[1], price);
+    market.buy(USERS[0], price, "Shoe".to_string(), 1, "delivery_address".to_string(), None);
+    market.buy(USERS[0], price, "Boot".to_string(), 1, "delivery_address".to_string(), None);
+    market.buy(USERS[1], price, "Boot".to_string(), 1, "delivery_address".to_string(), None);
+    market.delete_product(ADMIN, "Shoe".to_string(), None);
+
+    let reply: StateReply = market.read_state(StateQuery::GetOrphanedPurchases).expect("Unexpected invalid state.");
+    let StateReply::Purchases { purchases, truncated: false } = reply else {
+        panic!("Unexpected reply to `GetOrphanedPurchases`");
+    };
+    assert_eq!(purchases.len(), 1);
+    assert_eq!(purchases[0].0, USERS[0].into());
+    assert_eq!(purchases[0].1.len(), 1);
+    assert_eq!(purchases[0].1[0].name, "Shoe");
+}
+
+#[test]
+fn rounding_modes() {
+    // a 2.5% fee on 1005 is 25.125
+    assert_eq!(RoundingMode::Floor.mul_div(1005, 250, 10_000), Some(25));
+    assert_eq!(RoundingMode::Ceil.mul_div(1005, 250, 10_000), Some(26));
+    assert_eq!(RoundingMode::Nearest.mul_div(1005, 250, 10_000), Some(25));
+    // and on 1020 it's 25.5
+    assert_eq!(RoundingMode::Floor.mul_div(1020, 250, 10_000), Some(25));
+    assert_eq!(RoundingMode::Ceil.mul_div(1020, 250, 10_000), Some(26));
+    assert_eq!(RoundingMode::Nearest.mul_div(1020, 250, 10_000), Some(26));
+    // even amounts aren't affected
+    for mode in [RoundingMode::Floor, RoundingMode::Ceil, RoundingMode::Nearest] {
+        assert_eq!(mode.mul_div(1000, 250, 10_000), Some(25));
+        assert_eq!(mode.mul_div(1000, 250, 0), None);
+        assert_eq!(mode.mul_div(u128::MAX, 2, 10_000), None);
+    }
+
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        rounding: RoundingMode::Ceil,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.config.rounding, RoundingMode::Ceil);
+}
+
+#[test]
+fn max_contract_value() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let price = 10_000_000_000_000;
+    let config = Config {
+        public_key: "public key".to_string(),
+        max_contract_value: Some(2*price),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 3*price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), Some(MarketError::ContractValueCapReached));
+    system.claim_value_from_mailbox(USERS[0]);
+
+    // paying out frees up room under the cap
+    let result = market.send(ADMIN, MarketAction::Payout { seller: ADMIN.into() });
+    assert!(!result.main_failed());
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+}
+
+#[test]
+fn restock_subscriptions() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 1, price, None);
+    let result = market.send(USERS[0], MarketAction::SubscribeRestock { name: "Product_#2".to_string() });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::ThereIsNoSuchName).encode())));
+    for user in USERS {
+        let result = market.send(user, MarketAction::SubscribeRestock { name: "Product_#1".to_string() });
+        assert!(has_event(&result, user, &MarketEvent::RestockSubscribed { name: "Product_#1".to_string() }));
+    }
+    let result = market.send(USERS[2], MarketAction::UnsubscribeRestock { name: "Product_#1".to_string() });
+    assert!(has_event(&result, USERS[2], &MarketEvent::RestockUnsubscribed { name: "Product_#1".to_string() }));
+
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Product_#1".to_string(), quantity: 2 });
+    let notification = MarketEvent::BackInStock { name: "Product_#1".to_string() };
+    assert!(has_event(&result, USERS[0], &notification));
+    assert!(has_event(&result, USERS[1], &notification));
+    assert!(!has_event(&result, USERS[2], &notification));
+
+    // the subscriptions are used up
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Product_#1".to_string(), quantity: 2 });
+    assert!(!has_event(&result, USERS[0], &notification));
+
+    // stock coming back any other way notifies too
+    let result = market.send(USERS[1], MarketAction::SubscribeRestock { name: "Product_#1".to_string() });
+    assert!(!result.main_failed());
+    market.update_product_info(ADMIN, "Product_#1".to_string(), Some(0), None, None);
+    let result = market.send(ADMIN, MarketAction::UpdateProductInfo { name: "Product_#1".to_string(), quantity: Some(3), price: None });
+    assert!(has_event(&result, USERS[1], &notification));
+}
+
+#[test]
+fn currency_display() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    for (currency_symbol, decimals) in [("VARA-TOKEN", 12), ("VARA", 31)] {
+        let config = Config { public_key: "public key".to_string(), currency_symbol: currency_symbol.to_string(), decimals, ..Default::default() };
+        let result = market.send(ADMIN, MarketAction::UpdateConfig { config });
+        assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidConfig).encode())));
+    }
+    let config = Config { public_key: "public key".to_string(), currency_symbol: "VARA".to_string(), decimals: 12, ..Default::default() };
+    let result = market.send(ADMIN, MarketAction::UpdateConfig { config });
+    assert!(!result.main_failed());
+
+    let reply: StateReply = market.read_state(StateQuery::GetConfig).expect("Unexpected invalid state.");
+    let StateReply::Config(config) = reply else {
+        panic!("Unexpected reply to `GetConfig`");
+    };
+    assert_eq!((config.currency_symbol.as_str(), config.decimals), ("VARA", 12));
+}
+
+#[test]
+fn exact_payment() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 2*price);
+    let buy = MarketAction::Buy { name: "Product_#1".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: true, use_credit: false, redeem_points: 0, verbose: false };
+    let result = market.send_with_value(USERS[0], buy, 2*price);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::InexactPayment).encode())));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), 2*price);
+
+    let buy = MarketAction::Buy { name: "Product_#1".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: true, use_credit: false, redeem_points: 0, verbose: false };
+    let result = market.send_with_value(USERS[0], buy, price);
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderPlaced { order_id: 0 }));
+    // nothing but the reply is sent back
+    assert_eq!(result.log().iter().filter(|log| log.destination() == USERS[0].into()).count(), 1);
+}
+
+#[test]
+fn product_attributes() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        max_attributes: 2,
+        max_attribute_len: 8,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 10, price, None);
+    let set = |key: &str, value: &str| MarketAction::SetProductAttribute { name: "Shoe".to_string(), key: key.to_string(), value: value.to_string() };
+    let result = market.send(USERS[0], set("color", "red"));
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, set("color", "ultramarine"));
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidAttribute).encode())));
+    let result = market.send(ADMIN, set("color", "red"));
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductAttributeSet { name: "Shoe".to_string(), key: "color".to_string(), value: "red".to_string() }));
+    let result = market.send(ADMIN, set("size", "42"));
+    assert!(!result.main_failed());
+    let result = market.send(ADMIN, set("weight", "1kg"));
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::TooManyAttributes).encode())));
+    // overwriting doesn't count against the limit
+    let result = market.send(ADMIN, set("color", "blue"));
+    assert!(!result.main_failed());
+
+    let result = market.send(ADMIN, MarketAction::RemoveProductAttribute { name: "Shoe".to_string(), key: "weight".to_string() });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::ThereIsNoSuchAttribute).encode())));
+    let result = market.send(ADMIN, MarketAction::RemoveProductAttribute { name: "Shoe".to_string(), key: "size".to_string() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductAttributeRemoved { name: "Shoe".to_string(), key: "size".to_string() }));
+
+    let reply: StateReply = market.read_state(StateQuery::GetProduct { name: "Shoe".to_string(), lang: None }).expect("Unexpected invalid state.");
+    let StateReply::Product(Some((_, product_data))) = reply else {
+        panic!("Unexpected reply to `GetProduct`");
+    };
+    assert_eq!(product_data.attributes, vec![("color".to_string(), "blue".to_string())]);
+}
+
+#[test]
+fn update_purchase_status_batch() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    let updates = vec![(0, Status::Shipped), (1, Status::Delivered), (7, Status::Shipped)];
+    let result = market.send(USERS[0], MarketAction::UpdatePurchaseStatusBatch(updates.clone()));
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatusBatch(updates));
+    let event = MarketEvent::StatusBatchUpdated { succeeded: vec![0], failed: vec![(1, MarketError::IllegalStatusTransition), (7, MarketError::ThereIsNoSuchOrder)] };
+    assert!(has_event(&result, ADMIN, &event));
+
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(matches!(state.purchases[0].1[0].status, Status::Shipped));
+    assert!(matches!(state.purchases[0].1[1].status, Status::PaidFor));
+}
+
+#[test]
+fn invoice() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 3*price);
+    market.buy(USERS[0], 3*price, "Product_#1".to_string(), 3, "delivery_address".to_string(), None);
+    // a later price change doesn't affect the invoice
+    market.update_product_info(ADMIN, "Product_#1".to_string(), None, Some(2*price), None);
+
+    let reply: StateReply = market.read_state(StateQuery::GetInvoice(0)).expect("Unexpected invalid state.");
+    let StateReply::Invoice(Some(invoice)) = reply else {
+        panic!("Unexpected reply to `GetInvoice`");
+    };
+    assert_eq!((invoice.order_id, invoice.buyer, invoice.seller), (0, USERS[0].into(), ADMIN.into()));
+    assert_eq!(invoice.lines.len(), 1);
+    assert_eq!((invoice.lines[0].name.as_str(), invoice.lines[0].quantity, invoice.lines[0].unit_price), ("Product_#1", 3, price));
+    assert_eq!((invoice.subtotal, invoice.total), (3*price, 3*price));
+
+    let reply: StateReply = market.read_state(StateQuery::GetInvoice(1)).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Invoice(None)));
+}
+
+#[test]
+fn complete_state() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    // a small market fits in one reply
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(state.complete);
+    assert_eq!(state.schema_version, STATE_SCHEMA_VERSION);
+    assert_eq!((state.products.len(), state.purchases.len()), (1, 1));
+}
+
+#[test]
+fn admin_actions() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    market.update_product_info(ADMIN, "Product_#1".to_string(), None, Some(2*price), None);
+    // neither are failed actions nor rejected attempts
+    let result = market.send(ADMIN, MarketAction::UpdateConfig { config: Config::default() });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidConfig).encode())));
+    market.delete_product(ADMIN, "Product_#2".to_string(), Some(MarketError::ThereIsNoSuchName));
+    let result = market.send(USERS[0], MarketAction::DeleteProduct { name: "Product_#1".to_string() });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+
+    let reply: StateReply = market.read_state(StateQuery::GetAdminActions { limit: 10 }).expect("Unexpected invalid state.");
+    let StateReply::AdminActions(actions) = reply else {
+        panic!("Unexpected reply to `GetAdminActions`");
+    };
+    let actions: Vec<_> = actions.into_iter().map(|(_, who, action)| (who, action)).collect();
+    assert_eq!(actions, vec![(ADMIN.into(), "AddProduct".to_string()), (ADMIN.into(), "UpdateProductInfo".to_string())]);
+
+    let reply: StateReply = market.read_state(StateQuery::GetAdminActions { limit: 1 }).expect("Unexpected invalid state.");
+    let StateReply::AdminActions(actions) = reply else {
+        panic!("Unexpected reply to `GetAdminActions`");
+    };
+    assert_eq!(actions.len(), 1);
+    assert_eq!(actions[0].2, "UpdateProductInfo");
+}
+
+#[test]
+fn sweep_unaccounted_funds() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    // value attached to an action that doesn't take any stays in the market
+    let result = market.send_with_value(USERS[0], MarketAction::Payout { seller: USERS[0].into() }, price);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NothingToPayout).encode())));
+
+    let result = market.send(USERS[1], MarketAction::SweepUnaccountedFunds { to: USERS[1].into() });
+    assert!(result.contains(&(USERS[1], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+
+    let result = market.send(ADMIN, MarketAction::SweepUnaccountedFunds { to: USERS[1].into() });
+    assert!(!result.main_failed());
+    system.claim_value_from_mailbox(USERS[1]);
+    let swept = system.balance_of(USERS[1]);
+    assert!(swept != 0 && swept <= price);
+
+    // the seller's proceeds are untouched
+    let result = market.send(ADMIN, MarketAction::Payout { seller: ADMIN.into() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::PayoutSent { seller: ADMIN.into(), amount: price }));
+    let result = market.send(ADMIN, MarketAction::SweepUnaccountedFunds { to: USERS[1].into() });
+    assert!(!has_event(&result, ADMIN, &MarketEvent::FundsSwept { to: USERS[1].into(), amount: price }));
+}
+
+#[test]
+fn hashed_order_ids() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        order_id_mode: OrderIdMode::Hashed,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 2*price);
+    for _ in 0..2 {
+        let result = market.send_with_value(USERS[0], MarketAction::Buy { name: "Product_#1".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: false, use_credit: false, redeem_points: 0, verbose: true }, price);
+        assert!(!result.main_failed());
+    }
+
+    // the count of orders stays private
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    let order_ids: Vec<u128> = state.purchases[0].1.iter().map(|purchase| purchase.order_id).collect();
+    assert_eq!(order_ids.len(), 2);
+    assert_eq!(state.next_order_id, 0);
+    assert_ne!(order_ids[0], order_ids[1]);
+    assert!(order_ids.iter().all(|order_id| *order_id > 1));
+
+    // the ids look orders up like sequential ones
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: order_ids[1] });
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderCancelled { order_id: order_ids[1], refund: price, refund_percent: 10_000 }));
+}
+
+#[test]
+fn compare_products() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        max_query_batch: 3,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 10, price, None);
+    market.add_product(ADMIN, "Boot".to_string(), 5, 2*price, None);
+
+    let names = vec!["Boot".to_string(), "Hat".to_string(), "Shoe".to_string()];
+    let reply: StateReply = market.read_state(StateQuery::CompareProducts(names)).expect("Unexpected invalid state.");
+    let StateReply::ProductComparison { products, truncated: false } = reply else {
+        panic!("Unexpected reply to `CompareProducts`");
+    };
+    let products: Vec<_> = products.into_iter().map(|product| product.map(|product_data| (product_data.quantity, product_data.price))).collect();
+    assert_eq!(products, vec![Some((5, 2*price)), None, Some((10, price))]);
+
+    // names past the cap are left out
+    let names = vec!["Boot".to_string(), "Hat".to_string(), "Shoe".to_string(), "Boot".to_string()];
+    let reply: StateReply = market.read_state(StateQuery::CompareProducts(names)).expect("Unexpected invalid state.");
+    let StateReply::ProductComparison { products, truncated: true } = reply else {
+        panic!("Unexpected reply to `CompareProducts`");
+    };
+    assert_eq!(products.len(), 3);
+}
+
+#[test]
+fn tracking_numbers() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        max_tracking_len: 8,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 0, status: Status::Shipped, tracking_number: Some("TOO-LONG-123".to_string()) });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidTrackingNumber).encode())));
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 0, status: Status::Shipped, tracking_number: Some("TRK-1".to_string()) });
+    assert!(has_event(&result, ADMIN, &MarketEvent::PurchaseStatusUpdated { order_id: 0, status: Status::Shipped }));
+    // the number is only attached on shipping
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 0, status: Status::Delivered, tracking_number: Some("TRK-2".to_string()) });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidTrackingNumber).encode())));
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 0, status: Status::Delivered, tracking_number: None });
+    assert!(!result.main_failed());
+
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.purchases[0].1[0].tracking_number.as_deref(), Some("TRK-1"));
+}
+
+#[test]
+fn products_sorted_by() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Boot".to_string(), 5, 3*price, None);
+    market.add_product(ADMIN, "Hat".to_string(), 20, price, None);
+    market.add_product(ADMIN, "Shoe".to_string(), 10, 2*price, None);
+
+    let sorted = |field: SortField, descending: bool, offset: u32, limit: u32| -> (Vec<String>, bool) {
+        let reply: StateReply = market.read_state(StateQuery::GetProductsSortedBy { field, descending, offset, limit }).expect("Unexpected invalid state.");
+        let StateReply::Products { products, truncated } = reply else {
+            panic!("Unexpected reply to `GetProductsSortedBy`");
+        };
+        (products.into_iter().map(|(name, _)| name).collect(), truncated)
+    };
+    assert_eq!(sorted(SortField::Name, false, 0, 10), (vec!["Boot".to_string(), "Hat".to_string(), "Shoe".to_string()], false));
+    assert_eq!(sorted(SortField::Price, false, 0, 10), (vec!["Hat".to_string(), "Shoe".to_string(), "Boot".to_string()], false));
+    assert_eq!(sorted(SortField::Quantity, true, 0, 2), (vec!["Hat".to_string(), "Shoe".to_string()], true));
+    assert_eq!(sorted(SortField::Quantity, true, 2, 2), (vec!["Boot".to_string()], false));
+}
+
+#[test]
+fn store_credit() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+
+    let result = market.send(USERS[0], MarketAction::GrantCredit { actor: USERS[0].into(), amount: price });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::GrantCredit { actor: USERS[0].into(), amount: price });
+    assert!(has_event(&result, ADMIN, &MarketEvent::CreditGranted { actor: USERS[0].into(), amount: price, balance: price }));
+
+    // the credit covers one unit, the value the other
+    system.mint_to(USERS[0], price);
+    let result = market.send_with_value(USERS[0], MarketAction::Buy { name: "Product_#1".to_string(), quantity: 2, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: true, use_credit: true, redeem_points: 0, verbose: false }, price);
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderPlaced { order_id: 0 }));
+    assert!(has_event(&result, USERS[0], &MarketEvent::CreditUsed { order_id: 0, amount: price, balance: 0 }));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.pending_payouts, vec![(ADMIN.into(), price)]);
+    assert_eq!((state.purchases[0].1[0].total_paid, state.purchases[0].1[0].credit_used), (price, price));
+
+    // cancelling refunds the value and gives the credit back
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 0 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderCancelled { order_id: 0, refund: price, refund_percent: 10_000 }));
+    let result = market.send(ADMIN, MarketAction::GrantCredit { actor: USERS[0].into(), amount: 1 });
+    assert!(has_event(&result, ADMIN, &MarketEvent::CreditGranted { actor: USERS[0].into(), amount: 1, balance: price + 1 }));
+}
+
+#[test]
+fn product_expiry() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Milk".to_string(), 10, price, None);
+    market.add_product(ADMIN, "Bread".to_string(), 10, price, None);
+    let result = market.send(ADMIN, MarketAction::SetProductExpiry { name: "Milk".to_string(), expires_at: Some(system.block_timestamp()) });
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductExpirySet { name: "Milk".to_string(), expires_at: Some(system.block_timestamp()) }));
+    let result = market.send(ADMIN, MarketAction::SetProductExpiry { name: "Bread".to_string(), expires_at: Some(u64::MAX) });
+    assert!(!result.main_failed());
+
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], price, "Milk".to_string(), 1, "delivery_address".to_string(), Some(MarketError::ProductExpired));
+    market.buy(USERS[0], price, "Bread".to_string(), 1, "delivery_address".to_string(), None);
+
+    let reply: StateReply = market.read_state(StateQuery::GetExpiredProducts).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::ExpiredProducts(names) if names == vec!["Milk".to_string()]));
+}
+
+#[test]
+fn blocklist() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+
+    let result = market.send(ADMIN, MarketAction::BlockActor { actor: USERS[0].into() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::ActorBlocked { actor: USERS[0].into() }));
+    let reply: StateReply = market.read_state(StateQuery::IsBlocked(USERS[0].into())).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Blocked(true)));
+
+    // the value attached by a blocked buyer is returned
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), Some(MarketError::ActorBlocked));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), price);
+    let result = market.send(USERS[0], MarketAction::SubscribeRestock { name: "Product_#1".to_string() });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::ActorBlocked).encode())));
+
+    let result = market.send(ADMIN, MarketAction::UnblockActor { actor: USERS[0].into() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::ActorUnblocked { actor: USERS[0].into() }));
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    let reply: StateReply = market.read_state(StateQuery::IsBlocked(USERS[0].into())).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Blocked(false)));
+}
+
+#[test]
+fn category_stats() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    for (name, category) in [("Shoe", "Footwear"), ("Boot", "Footwear"), ("Hat", "Headwear")] {
+        market.add_product(ADMIN, name.to_string(), 10, price, None);
+        let result = market.send(ADMIN, MarketAction::SetProductCategory { name: name.to_string(), category: Some(category.to_string()) });
+        assert!(!result.main_failed());
+    }
+    system.mint_to(USERS[0], 3*price);
+    market.buy(USERS[0], 3*price, "Shoe".to_string(), 3, "delivery_address".to_string(), None);
+
+    let reply: StateReply = market.read_state(StateQuery::GetCategoryStats("Footwear".to_string())).expect("Unexpected invalid state.");
+    let expected = CategoryStats { product_count: 2, total_stock: 17, total_units_sold: 3, total_revenue: 3*price };
+    assert!(matches!(reply, StateReply::CategoryStats(Some(stats)) if stats == expected));
+    let reply: StateReply = market.read_state(StateQuery::GetCategoryStats("Toys".to_string())).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::CategoryStats(Some(stats)) if stats == CategoryStats::default()));
+}
+
+#[test]
+fn listing_delay() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        listing_delay_blocks: 3,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), Some(MarketError::ProductNotYetBuyable));
+
+    system.spend_blocks(3);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+}
+
+#[test]
+fn buyer_contact() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        max_contact_len: 16,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], price);
+    let buy = |contact: &str| MarketAction::Buy { name: "Product_#1".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: Some(contact.to_string()), max_unit_price: None, exact: false, use_credit: false, redeem_points: 0, verbose: false };
+    let result = market.send_with_value(USERS[0], buy("someone@example.com"), price);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::InvalidContact).encode())));
+    system.claim_value_from_mailbox(USERS[0]);
+    let result = market.send_with_value(USERS[0], buy("me@example.com"), price);
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderPlaced { order_id: 0 }));
+
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.purchases[0].1[0].contact.as_deref(), Some("me@example.com"));
+}
+
+#[test]
+fn buyer_count() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    for user in [USERS[0], USERS[1]] {
+        system.mint_to(user, price);
+        market.buy(user, price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    }
+    let result = market.send(USERS[1], MarketAction::CancelOrder { order_id: 1 });
+    assert!(!result.main_failed());
+
+    let reply: StateReply = market.read_state(StateQuery::GetBuyerCount { exclude_cancelled: false }).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Count(2)));
+    let reply: StateReply = market.read_state(StateQuery::GetBuyerCount { exclude_cancelled: true }).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Count(1)));
+}
+
+#[test]
+fn merge_products() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 10, price, None);
+    market.add_product(ADMIN, "Shoe (copy)".to_string(), 5, price, None);
+    let result = market.send(ADMIN, MarketAction::AddBundle { name: "Pair".to_string(), components: vec![("Shoe (copy)".to_string(), 2)], price });
+    assert!(!result.main_failed());
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Shoe (copy)".to_string(), 1, "delivery_address".to_string(), None);
+
+    let result = market.send(ADMIN, MarketAction::MergeProducts { from: "Shoe (copy)".to_string(), into: "Boot".to_string() });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::ThereIsNoSuchName).encode())));
+    let result = market.send(ADMIN, MarketAction::MergeProducts { from: "Shoe".to_string(), into: "Shoe".to_string() });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::SameProduct).encode())));
+    let result = market.send(ADMIN, MarketAction::MergeProducts { from: "Shoe (copy)".to_string(), into: "Shoe".to_string() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductsMerged { from: "Shoe (copy)".to_string(), into: "Shoe".to_string(), quantity: 4 }));
+
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products.len(), 1);
+    assert_eq!((state.products[0].1.quantity, state.products[0].1.units_sold), (14, 1));
+    assert_eq!(state.purchases[0].1[0].name, "Shoe");
+    assert_eq!(state.bundles[0].1.components, vec![("Shoe".to_string(), 2)]);
+}
+
+#[test]
+fn gas_diagnostics() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        include_gas_diagnostics: true,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let result = market.send(ADMIN, MarketAction::AddProduct { name: "Product_#1".to_string(), quantity: 10, price, max_supply: None, preorder: false, sku: String::new(), instant: false, delivery_payload: None });
+    let gas_used = result.log().iter().filter(|log| log.destination() == ADMIN.into()).find_map(|log| match Result::<SequencedEvent, MarketError>::decode(&mut log.payload()) {
+        Ok(Ok(SequencedEvent { gas_used, .. })) => gas_used,
+        _ => None,
+    });
+    assert!(gas_used.is_some_and(|gas_used| gas_used != 0));
+}
+
+#[test]
+fn products_with_quantity() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 1, price, None);
+    market.add_product(ADMIN, "Boot".to_string(), 1, price, None);
+    market.add_product(ADMIN, "Hat".to_string(), 5, price, None);
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], price, "Shoe".to_string(), 1, "delivery_address".to_string(), None);
+    market.buy(USERS[0], price, "Boot".to_string(), 1, "delivery_address".to_string(), None);
+
+    let reply: StateReply = market.read_state(StateQuery::GetProductsWithQuantity(0)).expect("Unexpected invalid state.");
+    let StateReply::Products { products, truncated: false } = reply else {
+        panic!("Unexpected reply to `GetProductsWithQuantity`");
+    };
+    let names: Vec<_> = products.into_iter().map(|(name, _)| name).collect();
+    assert_eq!(names, vec!["Boot".to_string(), "Shoe".to_string()]);
+}
+
+#[test]
+fn delivery_failed() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], 2*price, "Product_#1".to_string(), 2, "delivery_address".to_string(), None);
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 0, status: Status::Shipped, tracking_number: None });
+    assert!(!result.main_failed());
+
+    let result = market.send(USERS[0], MarketAction::MarkDeliveryFailed { order_id: 0 });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::MarkDeliveryFailed { order_id: 0 });
+    assert!(has_event(&result, ADMIN, &MarketEvent::DeliveryFailedRefunded { order_id: 0, buyer: USERS[0].into(), refund: 2*price }));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), 2*price);
+
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 10);
+    assert!(matches!(state.purchases[0].1[0].status, Status::DeliveryFailed));
+
+    // a terminal order can't be refunded twice
+    let result = market.send(ADMIN, MarketAction::MarkDeliveryFailed { order_id: 0 });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::IllegalStatusTransition).encode())));
+}
+
+#[test]
+fn balance_sheet() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 1, status: Status::Shipped, tracking_number: None });
+    assert!(!result.main_failed());
+
+    let reply: StateReply = market.read_state(StateQuery::GetBalanceSheet).expect("Unexpected invalid state.");
+    let StateReply::BalanceSheet(sheet) = reply else {
+        panic!("Unexpected reply to `GetBalanceSheet`");
+    };
+    assert_eq!((sheet.pending_payouts, sheet.escrowed), (2*price, price));
+    assert!(sheet.contract_balance >= sheet.pending_payouts);
+    assert_eq!(sheet.unaccounted, sheet.contract_balance - sheet.pending_payouts);
+}
+
+#[test]
+fn price_tiers() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 100, 10*price, None);
+    let tier = |min_quantity: u128, unit_price: u128| PriceTier { min_quantity, unit_price };
+    let result = market.send(ADMIN, MarketAction::SetPriceTiers { name: "Product_#1".to_string(), tiers: vec![tier(10, 9*price), tier(10, 8*price)] });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::DuplicateTierThreshold).encode())));
+    // a tier for more units may still be priced higher than one for fewer
+    let result = market.send(ADMIN, MarketAction::SetPriceTiers { name: "Product_#1".to_string(), tiers: vec![tier(20, 9*price), tier(10, 8*price)] });
+    assert!(has_event(&result, ADMIN, &MarketEvent::PriceTiersSet { name: "Product_#1".to_string(), tiers: vec![tier(10, 8*price), tier(20, 9*price)] }));
+
+    let unit_price = |quantity: u128| {
+        let reply: StateReply = market.read_state(StateQuery::QuoteBuy { name: "Product_#1".to_string(), quantity }).expect("Unexpected invalid state.");
+        let StateReply::Quote(Ok(quote)) = reply else {
+            panic!("Unexpected reply to `QuoteBuy`");
+        };
+        quote.unit_price
+    };
+    assert_eq!(unit_price(9), 10*price);
+    assert_eq!(unit_price(10), 8*price);
+    assert_eq!(unit_price(19), 8*price);
+    // both tiers apply, the lowest price wins
+    assert_eq!(unit_price(20), 8*price);
+}
+
+#[test]
+fn refund_percent() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    let result = market.send(ADMIN, MarketAction::SetRefundPercent { name: "Product_#1".to_string(), refund_percent: 10_001 });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidRefundPercent).encode())));
+    let result = market.send(ADMIN, MarketAction::SetRefundPercent { name: "Product_#1".to_string(), refund_percent: 7_500 });
+    assert!(has_event(&result, ADMIN, &MarketEvent::RefundPercentSet { name: "Product_#1".to_string(), refund_percent: 7_500 }));
+
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 0 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderCancelled { order_id: 0, refund: 3*price/4, refund_percent: 7_500 }));
+
+    // the seller keeps the rest
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.pending_payouts, vec![(ADMIN.into(), price/4)]);
+    assert_eq!(state.products[0].1.quantity, 10);
+}
+
+#[test]
+fn export_actor_data() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 1, price, None);
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Shoe".to_string(), 1, "delivery_address".to_string(), None);
+    let result = market.send(USERS[0], MarketAction::SubscribeRestock { name: "Shoe".to_string() });
+    assert!(!result.main_failed());
+    let result = market.send(ADMIN, MarketAction::GrantCredit { actor: USERS[0].into(), amount: price });
+    assert!(!result.main_failed());
+
+    let reply: StateReply = market.read_state(StateQuery::ExportActorData(USERS[0].into())).expect("Unexpected invalid state.");
+    let StateReply::ActorExport(export) = reply else {
+        panic!("Unexpected reply to `ExportActorData`");
+    };
+    assert_eq!(export.version, ACTOR_EXPORT_VERSION);
+    assert_eq!(export.purchases.len(), 1);
+    assert_eq!((export.total_spent, export.credit), (price, price));
+    assert_eq!(export.restock_subscriptions, vec!["Shoe".to_string()]);
+    assert!(!export.blocked);
+}
+
+#[test]
+fn restock_increment() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        restock_increment: Some(12),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Egg".to_string(), 12, price, None);
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Egg".to_string(), quantity: 18 });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidRestockIncrement).encode())));
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Egg".to_string(), quantity: 24 });
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductRestocked { name: "Egg".to_string(), quantity: 24 }));
+
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 36);
+}
+
+#[test]
+fn buy_cart() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Shoe".to_string(), 3, price, None);
+    market.add_product(ADMIN, "Hat".to_string(), 10, price, None);
+    let delivery_address = DeliveryAddress { country: String::new(), details: "delivery_address".to_string() };
+
+    // the first line takes 2 of the 3 shoes, so the second can't have 2 more
+    system.mint_to(USERS[0], 5*price);
+    let items = vec![("Shoe".to_string(), 2), ("Hat".to_string(), 1), ("Shoe".to_string(), 2)];
+    let result = market.send_with_value(USERS[0], MarketAction::BuyCart { items, delivery_address: delivery_address.clone() }, 5*price);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::CartItemUnavailable { name: "Shoe".to_string() }).encode())));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), 5*price);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(state.purchases.is_empty());
+
+    let items = vec![("Shoe".to_string(), 2), ("Hat".to_string(), 1), ("Shoe".to_string(), 1)];
+    let result = market.send_with_value(USERS[0], MarketAction::BuyCart { items, delivery_address }, 5*price);
+    assert!(has_event(&result, USERS[0], &MarketEvent::CartBought { order_ids: vec![0, 1, 2], total: 4*price }));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), price);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    let stock: Vec<_> = state.products.iter().map(|(name, product_data)| (name.as_str(), product_data.quantity)).collect();
+    assert!(stock.contains(&("Shoe", 0)) && stock.contains(&("Hat", 9)));
+}
+
+#[test]
+fn featured_products() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    for name in ["Shoe", "Boot", "Hat"] {
+        market.add_product(ADMIN, name.to_string(), 10, price, None);
+    }
+    let result = market.send(ADMIN, MarketAction::SetFeatured(vec!["Hat".to_string(), "Scarf".to_string()]));
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::ThereIsNoSuchName).encode())));
+    let result = market.send(ADMIN, MarketAction::SetFeatured(vec!["Hat".to_string(), "Shoe".to_string()]));
+    assert!(has_event(&result, ADMIN, &MarketEvent::FeaturedSet(vec!["Hat".to_string(), "Shoe".to_string()])));
+    let result = market.send(ADMIN, MarketAction::AddFeatured { name: "Boot".to_string() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::FeaturedSet(vec!["Hat".to_string(), "Shoe".to_string(), "Boot".to_string()])));
+    let result = market.send(ADMIN, MarketAction::RemoveFeatured { name: "Hat".to_string() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::FeaturedSet(vec!["Shoe".to_string(), "Boot".to_string()])));
+
+    // deleted products drop out
+    market.delete_product(ADMIN, "Shoe".to_string(), None);
+    let reply: StateReply = market.read_state(StateQuery::GetFeatured).expect("Unexpected invalid state.");
+    let StateReply::Featured(featured) = reply else {
+        panic!("Unexpected reply to `GetFeatured`");
+    };
+    let names: Vec<_> = featured.into_iter().map(|(name, _)| name).collect();
+    assert_eq!(names, vec!["Boot".to_string()]);
+}
+
+#[test]
+fn loyalty_points() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        points_divisor: 1_000,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+
+    // no points yet
+    system.mint_to(USERS[0], 2 * price);
+    let result = market.send_with_value(USERS[0], MarketAction::Buy { name: "Product_#1".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: true, use_credit: false, redeem_points: 1, verbose: false }, price);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::InsufficientPoints).encode())));
+
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    let earned = price / 1_000;
+    let reply: StateReply = market.read_state(StateQuery::GetLoyaltyPoints(USERS[0].into())).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::LoyaltyPoints(points) if points == earned));
+
+    // the points cover part of the second order and the value the rest
+    let result = market.send_with_value(USERS[0], MarketAction::Buy { name: "Product_#1".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: true, use_credit: false, redeem_points: earned, verbose: false }, price - earned);
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderPlaced { order_id: 1 }));
+    let earned_again = (price - earned) / 1_000;
+    let reply: StateReply = market.read_state(StateQuery::GetLoyaltyPoints(USERS[0].into())).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::LoyaltyPoints(points) if points == earned_again));
+
+    // cancelling gives the redeemed points back and takes the earned ones away
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 1 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderCancelled { order_id: 1, refund: price - earned, refund_percent: 10_000 }));
+    let reply: StateReply = market.read_state(StateQuery::GetLoyaltyPoints(USERS[0].into())).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::LoyaltyPoints(points) if points == earned));
+}
+
+#[test]
+fn unsold_products() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Milk".to_string(), 10, price, None);
+    market.add_product(ADMIN, "Bread".to_string(), 10, price, None);
+    market.add_product(ADMIN, "Apples".to_string(), 10, price, None);
+
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Bread".to_string(), 1, "delivery_address".to_string(), None);
+
+    let reply: StateReply = market.read_state(StateQuery::GetUnsoldProducts).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::UnsoldProducts(names) if names == vec!["Apples".to_string(), "Milk".to_string()]));
+}
+
+#[test]
+fn history_cap() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        max_history_per_buyer: Some(2),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 4*price);
+    for _ in 0..2 {
+        market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    }
+
+    // active orders are kept even past the cap
+    let result = market.send_with_value(USERS[0], MarketAction::Buy { name: "Product_#1".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: true, use_credit: false, redeem_points: 0, verbose: false }, price);
+    assert!(!has_event(&result, USERS[0], &MarketEvent::HistoryTrimmed { removed_count: 1 }));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.purchases[0].1.len(), 3);
+
+    // the next order drops the delivered one and keeps the history at the cap
+    for status in [Status::Shipped, Status::Delivered] {
+        let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 1, status, tracking_number: None });
+        assert!(!result.main_failed());
+    }
+    let result = market.send_with_value(USERS[0], MarketAction::Buy { name: "Product_#1".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: None, max_unit_price: None, exact: true, use_credit: false, redeem_points: 0, verbose: false }, price);
+    assert!(has_event(&result, USERS[0], &MarketEvent::HistoryTrimmed { removed_count: 1 }));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    let order_ids: Vec<u128> = state.purchases[0].1.iter().map(|purchase| purchase.order_id).collect();
+    assert_eq!(order_ids, vec![0, 2, 3]);
+}
+
+#[test]
+fn allowlisted_product() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let result = market.send(USERS[0], MarketAction::AddAllowlistedProduct { name: "Drop".to_string(), quantity: 10, price, allowlist: vec![USERS[0].into()] });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::AddAllowlistedProduct { name: "Drop".to_string(), quantity: 10, price, allowlist: vec![USERS[0].into()] });
+    assert!(has_event(&result, ADMIN, &MarketEvent::AllowlistedProductAdded { name: "Drop".to_string(), quantity: 10, price, allowlist: vec![USERS[0].into()] }));
+    let reply: StateReply = market.read_state(StateQuery::GetAdminActions { limit: 10 }).expect("Unexpected invalid state.");
+    let StateReply::AdminActions(actions) = reply else {
+        panic!("Unexpected reply to `GetAdminActions`");
+    };
+    let actions: Vec<_> = actions.into_iter().map(|(_, _, action)| action).collect();
+    assert_eq!(actions, vec!["AddAllowlistedProduct".to_string()]);
+
+    system.mint_to(USERS[0], price);
+    system.mint_to(USERS[1], 2*price);
+    market.buy(USERS[1], price, "Drop".to_string(), 1, "delivery_address".to_string(), Some(MarketError::NotOnAllowlist));
+    market.buy(USERS[0], price, "Drop".to_string(), 1, "delivery_address".to_string(), None);
+
+    // once lifted, anyone can buy
+    let result = market.send(ADMIN, MarketAction::LiftAllowlist { name: "Drop".to_string() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::AllowlistLifted { name: "Drop".to_string() }));
+    market.buy(USERS[1], price, "Drop".to_string(), 1, "delivery_address".to_string(), None);
+}
+
+#[test]
+fn simulate_buy() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+
+    let reply: StateReply = market.read_state(StateQuery::SimulateBuy { name: "Product_#1".to_string(), quantity: 3 }).expect("Unexpected invalid state.");
+    let StateReply::BuySimulation(Ok(simulation)) = reply else {
+        panic!("Unexpected reply to `SimulateBuy`");
+    };
+    assert_eq!(simulation.total_payment, 3*price);
+    assert_eq!(simulation.quantities_left, vec![("Product_#1".to_string(), 7)]);
+    let reply: StateReply = market.read_state(StateQuery::SimulateBuy { name: "Product_#1".to_string(), quantity: 11 }).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::BuySimulation(Err(MarketError::QuantityExceeded))));
+
+    // nothing was bought
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 10);
+}
+
+#[test]
+fn buy_validation_leaves_state_untouched() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let price = 10_000_000_000_000;
+    let config = Config {
+        public_key: "public key".to_string(),
+        max_spend_per_actor: Some(5*price),
+        max_contact_len: 8,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    let result = market.send(ADMIN, MarketAction::AddAllowlistedProduct { name: "Drop".to_string(), quantity: 10, price, allowlist: vec![USERS[1].into()] });
+    assert!(!result.main_failed());
+    let result = market.send(ADMIN, MarketAction::BlockActor { actor: USERS[2].into() });
+    assert!(!result.main_failed());
+
+    let buy = |name: &str, quantity: u128, contact: Option<&str>, max_unit_price: Option<u128>, exact: bool, redeem_points: u128| MarketAction::Buy { name: name.to_string(), quantity, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() }, contact: contact.map(str::to_string), max_unit_price, exact, use_credit: false, redeem_points, verbose: false };
+    let cases = [
+        (USERS[2], buy("Product_#1", 1, None, None, false, 0), price, MarketError::ActorBlocked),
+        (USERS[0], buy("Product_#1", 1, Some(""), None, false, 0), price, MarketError::InvalidContact),
+        (USERS[0], buy("Product_#1", 1, Some("far too long"), None, false, 0), price, MarketError::InvalidContact),
+        (USERS[0], buy("Product_#2", 1, None, None, false, 0), price, MarketError::ThereIsNoSuchName),
+        (USERS[0], buy("Product_#1", 0, None, None, false, 0), price, MarketError::ZeroQuantity),
+        (USERS[0], buy("Product_#1", 11, None, None, false, 0), 11*price, MarketError::QuantityExceeded),
+        (USERS[0], buy("Drop", 1, None, None, false, 0), price, MarketError::NotOnAllowlist),
+        (USERS[0], buy("Product_#1", 1, None, Some(price - 1), false, 0), price, MarketError::PriceAboveMax),
+        (USERS[0], buy("Product_#1", 1, None, None, false, 1), price, MarketError::InsufficientPoints),
+        (USERS[0], buy("Product_#1", 1, None, None, true, 0), 2*price, MarketError::InexactPayment),
+        (USERS[0], buy("Product_#1", 2, None, None, false, 0), price, MarketError::InsufficientValue),
+        (USERS[0], buy("Product_#1", 6, None, None, false, 0), 6*price, MarketError::SpendCapExceeded),
+    ];
+    for (user, action, value, error) in cases {
+        system.mint_to(user, value);
+        let result = market.send_with_value(user, action, value);
+        assert!(result.contains(&(user, Err::<MarketEvent, _>(error).encode())));
+    }
+
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(state.products.iter().all(|(_, product)| product.quantity == 10 && product.units_sold == 0));
+    assert!(state.purchases.is_empty());
+    assert!(state.total_spent.is_empty());
+    assert!(state.pending_payouts.is_empty());
+}
+
+#[test]
+fn instant_products() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let add = |preorder: bool, instant: bool, delivery_payload: Option<&str>| MarketAction::AddProduct { name: "E-book".to_string(), quantity: 10, price, max_supply: None, preorder, sku: String::new(), instant, delivery_payload: delivery_payload.map(str::to_string) };
+    let result = market.send(ADMIN, add(true, true, None));
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InstantPreorder).encode())));
+    let result = market.send(ADMIN, add(false, false, Some("https://example.com/e-book")));
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidDeliveryPayload).encode())));
+    let result = market.send(ADMIN, add(false, true, Some("")));
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidDeliveryPayload).encode())));
+    let result = market.send(ADMIN, add(false, true, Some("https://example.com/e-book")));
+    assert!(has_event(&result, ADMIN, &MarketEvent::ProductAdded { name: "E-book".to_string(), quantity: 10, price, max_supply: None, preorder: false, sku: String::new(), instant: true }));
+
+    // the order skips shipping and the buyer gets the payload
+    system.mint_to(USERS[0], price);
+    let result = market.send_with_value(USERS[0], MarketAction::Buy { name: "E-book".to_string(), quantity: 1, delivery_address: DeliveryAddress { country: String::new(), details: String::new() }, contact: None, max_unit_price: None, exact: true, use_credit: false, redeem_points: 0, verbose: false }, price);
+    assert!(has_event(&result, USERS[0], &MarketEvent::InstantlyDelivered { order_id: 0, delivery_payload: Some("https://example.com/e-book".to_string()) }));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(matches!(state.purchases[0].1[0].status, Status::Delivered));
+    let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 0, status: Status::Shipped, tracking_number: None });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::IllegalStatusTransition).encode())));
+}
+
+#[test]
+fn known_actor_purchases() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    let purchases = |actor: u64| {
+        let reply: StateReply = market.read_state(StateQuery::GetActorPurchases(actor.into())).expect("Unexpected invalid state.");
+        let StateReply::ActorPurchases(purchases) = reply else {
+            panic!("Unexpected reply to `GetActorPurchases`");
+        };
+        (purchases.known, purchases.purchases.len())
+    };
+    assert_eq!(purchases(USERS[0]), (true, 1));
+    assert_eq!(purchases(USERS[1]), (false, 0));
+
+    // a buyer whose history is gone is still known
+    for status in [Status::Shipped, Status::Delivered] {
+        let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 0, status, tracking_number: None });
+        assert!(!result.main_failed());
+    }
+    let result = market.send(USERS[0], MarketAction::ForgetMyHistory);
+    assert!(has_event(&result, USERS[0], &MarketEvent::HistoryForgotten { removed_count: 1 }));
+    assert_eq!(purchases(USERS[0]), (true, 0));
+}
+
+#[test]
+fn refund_tiers() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        refund_tiers: vec![(5, 5_000), (2, 10_000)],
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(result.main_failed());
+
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        refund_tiers: vec![(2, 10_000), (5, 5_000)],
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 3*price);
+    for _ in 0..3 {
+        market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    }
+
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 0 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderCancelled { order_id: 0, refund: price, refund_percent: 10_000 }));
+    system.spend_blocks(3);
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 1 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderCancelled { order_id: 1, refund: price/2, refund_percent: 5_000 }));
+    // still in the refund window, but past the last tier
+    system.spend_blocks(3);
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 2 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderCancelled { order_id: 2, refund: 0, refund_percent: 0 }));
+}
+
+#[test]
+fn rename_category() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    for name in ["Shoe", "Boot", "Hat"] {
+        market.add_product(ADMIN, name.to_string(), 10, price, None);
+    }
+    for (name, category) in [("Shoe", "Footwear"), ("Boot", "Footwear"), ("Hat", "Accessories")] {
+        let result = market.send(ADMIN, MarketAction::SetProductCategory { name: name.to_string(), category: Some(category.to_string()) });
+        assert!(!result.main_failed());
+    }
+
+    let result = market.send(USERS[0], MarketAction::RenameCategory { from: "Footwear".to_string(), into: "Shoes".to_string() });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotAdmin).encode())));
+    let result = market.send(ADMIN, MarketAction::RenameCategory { from: "Footwear".to_string(), into: String::new() });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidCategory).encode())));
+    let result = market.send(ADMIN, MarketAction::RenameCategory { from: "Footwear".to_string(), into: "Shoes".to_string() });
+    assert!(has_event(&result, ADMIN, &MarketEvent::CategoryRenamed { from: "Footwear".to_string(), into: "Shoes".to_string(), updated_count: 2 }));
+
+    let reply: StateReply = market.read_state(StateQuery::GetCategories).expect("Unexpected invalid state.");
+    let StateReply::Categories(categories) = reply else {
+        panic!("Unexpected reply to `GetCategories`");
+    };
+    assert_eq!(categories, vec!["Accessories".to_string(), "Shoes".to_string()]);
+}
+
+#[test]
+fn unit_scale() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        rounding: RoundingMode::Ceil,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    // cheese by the gram, priced per kilogram
+    let price = 10_000_000_000_001;
+    market.add_product(ADMIN, "Cheese".to_string(), 5_000, price, None);
+    let result = market.send(ADMIN, MarketAction::SetUnitScale { name: "Cheese".to_string(), unit_scale: 0 });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidUnitScale).encode())));
+    let result = market.send(ADMIN, MarketAction::SetUnitScale { name: "Cheese".to_string(), unit_scale: price + 1 });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::InvalidUnitScale).encode())));
+    let result = market.send(ADMIN, MarketAction::SetUnitScale { name: "Cheese".to_string(), unit_scale: 1_000 });
+    assert!(has_event(&result, ADMIN, &MarketEvent::UnitScaleSet { name: "Cheese".to_string(), unit_scale: 1_000 }));
+
+    let total = |quantity: u128| {
+        let reply: StateReply = market.read_state(StateQuery::QuoteBuy { name: "Cheese".to_string(), quantity }).expect("Unexpected invalid state.");
+        let StateReply::Quote(Ok(quote)) = reply else {
+            panic!("Unexpected reply to `QuoteBuy`");
+        };
+        quote.total
+    };
+    // rounded up, as configured
+    assert_eq!(total(1_000), price);
+    assert_eq!(total(1_250), price + price/4 + 1);
+    // a gram comes to less than the existential deposit
+    let reply: StateReply = market.read_state(StateQuery::QuoteBuy { name: "Cheese".to_string(), quantity: 1 }).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Quote(Err(MarketError::PriceLessThanExistentialDeposit))));
+
+    system.mint_to(USERS[0], price + price/4 + 1);
+    market.buy(USERS[0], price + price/4 + 1, "Cheese".to_string(), 1_250, "delivery_address".to_string(), None);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 3_750);
+}
+
+#[test]
+fn order_timeline() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], price);
+    let since = u64::from(system.block_height());
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    system.spend_blocks(2);
+    for status in [Status::Shipped, Status::Delivered] {
+        let result = market.send(ADMIN, MarketAction::UpdatePurchaseStatus { order_id: 0, status, tracking_number: None });
+        assert!(!result.main_failed());
+    }
+
+    let reply: StateReply = market.read_state(StateQuery::GetOrderTimeline(0)).expect("Unexpected invalid state.");
+    let StateReply::OrderTimeline(Some(timeline)) = reply else {
+        panic!("Unexpected reply to `GetOrderTimeline`");
+    };
+    assert_eq!(timeline.len(), 3);
+    assert!(matches!(timeline[0], (block, Status::PaidFor) if block >= since));
+    assert!(matches!(timeline[1], (block, Status::Shipped) if block >= timeline[0].0 + 2));
+    assert!(matches!(timeline[2], (block, Status::Delivered) if block >= timeline[1].0));
+
+    let reply: StateReply = market.read_state(StateQuery::GetOrderTimeline(1)).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::OrderTimeline(None)));
+}
+
+#[test]
+fn reservations() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        reservation_blocks: Some(10),
+        min_deposit_percent: 2_000,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 5*price);
+    system.mint_to(USERS[1], 7*price);
+
+    // a fifth of the price is the least deposit
+    let result = market.send_with_value(USERS[0], MarketAction::ReserveWithDeposit { name: "Product_#1".to_string(), quantity: 5, deposit: price - 1, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() } }, price - 1);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::DepositTooLow).encode())));
+    system.claim_value_from_mailbox(USERS[0]);
+    let result = market.send_with_value(USERS[0], MarketAction::ReserveWithDeposit { name: "Product_#1".to_string(), quantity: 5, deposit: price, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() } }, price);
+    assert!(!result.main_failed());
+    let reply: StateReply = market.read_state(StateQuery::GetReservation(0)).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Reservation(Some(Reservation { quantity: 5, deposit, .. })) if deposit == price));
+    market.buy(USERS[1], 6*price, "Product_#1".to_string(), 6, "delivery_address".to_string(), Some(MarketError::QuantityExceeded));
+
+    let result = market.send(USERS[0], MarketAction::ForfeitReservation { reservation_id: 0 });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::ReservationNotExpired).encode())));
+
+    // the deposit counts towards the price
+    let result = market.send_with_value(USERS[0], MarketAction::CompleteReservation { reservation_id: 0 }, 4*price);
+    assert!(has_event(&result, USERS[0], &MarketEvent::Bought { buyer: USERS[0].into(), name: "Product_#1".to_string(), quantity: 5, order_id: 0, tip: 0 }));
+    let reply: StateReply = market.read_state(StateQuery::GetReservation(0)).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Reservation(None)));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 5);
+    assert_eq!(state.pending_payouts, vec![(ADMIN.into(), 5*price)]);
+
+    // a reservation left to expire is forfeited and its units go back on sale
+    system.claim_value_from_mailbox(USERS[1]);
+    let result = market.send_with_value(USERS[1], MarketAction::ReserveWithDeposit { name: "Product_#1".to_string(), quantity: 5, deposit: price, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() } }, price);
+    assert!(!result.main_failed());
+    let reply: StateReply = market.read_state(StateQuery::GetActiveReservations(USERS[1].into())).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Reservations(reservations) if matches!(reservations.as_slice(), [(1, Reservation { quantity: 5, .. })])));
+    system.spend_blocks(11);
+    let reply: StateReply = market.read_state(StateQuery::GetActiveReservations(USERS[1].into())).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Reservations(reservations) if reservations.is_empty()));
+    let reply: StateReply = market.read_state(StateQuery::GetReservation(1)).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Reservation(None)));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 5);
+    assert_eq!(state.pending_payouts, vec![(ADMIN.into(), 6*price)]);
+
+    // reservations go through the purchase checks
+    let result = market.send(ADMIN, MarketAction::AddAllowlistedProduct { name: "Drop".to_string(), quantity: 10, price, allowlist: vec![USERS[1].into()] });
+    assert!(!result.main_failed());
+    let result = market.send_with_value(USERS[0], MarketAction::ReserveWithDeposit { name: "Drop".to_string(), quantity: 1, deposit: price, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() } }, price);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::NotOnAllowlist).encode())));
+    system.claim_value_from_mailbox(USERS[0]);
+    let result = market.send(USERS[1], MarketAction::ReserveWithDeposit { name: "Drop".to_string(), quantity: 1, deposit: 0, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() } });
+    assert!(result.contains(&(USERS[1], Err::<MarketEvent, _>(MarketError::DepositTooLow).encode())));
+}
+
+#[test]
+fn removing_a_product_refunds_its_reservations() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        reservation_blocks: Some(10),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], price);
+    let result = market.send_with_value(USERS[0], MarketAction::ReserveWithDeposit { name: "Product_#1".to_string(), quantity: 5, deposit: price, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() } }, price);
+    assert!(!result.main_failed());
+
+    market.delete_product(ADMIN, "Product_#1".to_string(), None);
+    let reply: StateReply = market.read_state(StateQuery::GetReservation(0)).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::Reservation(None)));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), price);
+
+    // the forfeiture scheduled for the reservation finds nothing to forfeit
+    system.spend_blocks(11);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(state.pending_payouts.is_empty());
+}
+
+#[test]
+fn removing_a_product_refunds_its_preorders() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    let result = market.send(ADMIN, MarketAction::AddProduct { name: "Console".to_string(), quantity: 0, price, max_supply: None, preorder: true, sku: String::new(), instant: false, delivery_payload: None });
+    assert!(!result.main_failed());
+    system.mint_to(USERS[0], 2*price);
+    market.buy(USERS[0], 2*price, "Console".to_string(), 2, "delivery_address".to_string(), None);
+
+    market.delete_product(ADMIN, "Console".to_string(), None);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    let purchase = state.purchases.into_iter().flat_map(|(_, purchases)| purchases).find(|purchase| purchase.order_id == 0).expect("Unexpected missing purchase.");
+    assert!(matches!(purchase.status, Status::Cancelled));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), 2*price);
+
+    // a product added under the same name doesn't fill the old preorder
+    let result = market.send(ADMIN, MarketAction::AddProduct { name: "Console".to_string(), quantity: 0, price, max_supply: None, preorder: true, sku: String::new(), instant: false, delivery_payload: None });
+    assert!(!result.main_failed());
+    let result = market.send(ADMIN, MarketAction::RestockProduct { name: "Console".to_string(), quantity: 2 });
+    assert!(!result.main_failed());
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 2);
+}
+
+#[test]
+fn reservation_caps_and_cancellation() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let price = 10_000_000_000_000;
+    let config = Config {
+        public_key: "public key".to_string(),
+        reservation_blocks: Some(10),
+        max_spend_per_actor: Some(price),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 4*price);
+    let reserve = |deposit: u128| MarketAction::ReserveWithDeposit { name: "Product_#1".to_string(), quantity: 2, deposit, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() } };
+
+    // deposits count towards the caps like payments
+    let result = market.send_with_value(USERS[0], reserve(2*price), 2*price);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::SpendCapExceeded).encode())));
+    system.claim_value_from_mailbox(USERS[0]);
+
+    // a reservation the product was frozen under is refunded
+    let result = market.send_with_value(USERS[0], reserve(price), price);
+    assert!(!result.main_failed());
+    let result = market.send(ADMIN, MarketAction::FreezeProduct { name: "Product_#1".to_string(), frozen: true });
+    assert!(!result.main_failed());
+    let result = market.send(USERS[1], MarketAction::CancelReservation { reservation_id: 0 });
+    assert!(result.contains(&(USERS[1], Err::<MarketEvent, _>(MarketError::ThereIsNoSuchReservation).encode())));
+    let result = market.send(USERS[0], MarketAction::CancelReservation { reservation_id: 0 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::ReservationCancelled { reservation_id: 0, refund: price }));
+    system.claim_value_from_mailbox(USERS[0]);
+    assert_eq!(system.balance_of(USERS[0]), 4*price);
+
+    // one that could still be completed isn't
+    let result = market.send(ADMIN, MarketAction::FreezeProduct { name: "Product_#1".to_string(), frozen: false });
+    assert!(!result.main_failed());
+    let result = market.send_with_value(USERS[0], reserve(price), price);
+    assert!(!result.main_failed());
+    let result = market.send(USERS[0], MarketAction::CancelReservation { reservation_id: 1 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::ReservationCancelled { reservation_id: 1, refund: 0 }));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 10);
+    assert_eq!(state.pending_payouts, vec![(ADMIN.into(), price)]);
+}
+
+#[test]
+fn forfeited_reservation_restocks() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        reservation_blocks: Some(10),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 5, price, None);
+    system.mint_to(USERS[0], price);
+    let result = market.send_with_value(USERS[0], MarketAction::ReserveWithDeposit { name: "Product_#1".to_string(), quantity: 5, deposit: price, delivery_address: DeliveryAddress { country: String::new(), details: "delivery_address".to_string() } }, price);
+    assert!(!result.main_failed());
+    let result = market.send(USERS[1], MarketAction::SubscribeRestock { name: "Product_#1".to_string() });
+    assert!(!result.main_failed());
+
+    // the units go back on sale like restocked ones
+    let results = system.spend_blocks(11);
+    let notification = MarketEvent::BackInStock { name: "Product_#1".to_string() };
+    assert!(results.iter().any(|result| has_event(result, USERS[1], &notification)));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.quantity, 5);
+}
+
+#[test]
+fn open_orders_cap() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        max_open_orders: Some(2),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 6*price);
+    for _ in 0..2 {
+        market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    }
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), Some(MarketError::TooManyOpenOrders));
+    system.claim_value_from_mailbox(USERS[0]);
+
+    // finished orders free their slots
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 0 });
+    assert!(!result.main_failed());
+    let delivery_address = DeliveryAddress { country: String::new(), details: "delivery_address".to_string() };
+    let items = vec![("Product_#1".to_string(), 1), ("Product_#1".to_string(), 1)];
+    let result = market.send_with_value(USERS[0], MarketAction::BuyCart { items, delivery_address }, 2*price);
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::TooManyOpenOrders).encode())));
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    // orders can't be pushed onto a buyer with no slots left
+    system.mint_to(USERS[1], 2*price);
+    for _ in 0..2 {
+        market.buy(USERS[1], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    }
+    let result = market.send(USERS[0], MarketAction::TransferPurchase { order_id: 1, to: USERS[1].into() });
+    assert!(result.contains(&(USERS[0], Err::<MarketEvent, _>(MarketError::TooManyOpenOrders).encode())));
+}
+
+#[test]
+fn scheduled_price_change() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    let now = u64::from(system.block_height());
+    let result = market.send(ADMIN, MarketAction::SchedulePriceChange { name: "Product_#1".to_string(), new_price: 2*price, effective_block: now });
+    assert!(result.contains(&(ADMIN, Err::<MarketEvent, _>(MarketError::EffectiveBlockPassed).encode())));
+    let result = market.send(ADMIN, MarketAction::SchedulePriceChange { name: "Product_#1".to_string(), new_price: 2*price, effective_block: now + 5 });
+    assert!(has_event(&result, ADMIN, &MarketEvent::PriceChangeScheduled { name: "Product_#1".to_string(), new_price: 2*price, effective_block: now + 5 }));
+
+    let reply: StateReply = market.read_state(StateQuery::GetScheduledPriceChanges).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::ScheduledPriceChanges(changes) if changes == vec![("Product_#1".to_string(), now + 5, 2*price)]));
+    system.mint_to(USERS[0], 3*price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    // the new price applies from its block on, with nobody sending anything then
+    system.spend_blocks(5);
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert_eq!(state.products[0].1.price, 2*price);
+    let reply: StateReply = market.read_state(StateQuery::GetScheduledPriceChanges).expect("Unexpected invalid state.");
+    assert!(matches!(reply, StateReply::ScheduledPriceChanges(changes) if changes.is_empty()));
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), Some(MarketError::InsufficientValue));
+    system.claim_value_from_mailbox(USERS[0]);
+    market.buy(USERS[0], 2*price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+}
+
+#[test]
+fn canonical_purchase_order() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    for user in [USERS[0], USERS[1], USERS[1], USERS[2]] {
+        system.mint_to(user, price);
+        market.buy(user, price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+    }
+    // the transferred order ends up after the later ones in the buyer's list
+    let result = market.send(USERS[0], MarketAction::TransferPurchase { order_id: 0, to: USERS[1].into() });
+    assert!(!result.main_failed());
+
+    let reply: StateReply = market.read_state(StateQuery::GetPurchases).expect("Unexpected invalid state.");
+    let StateReply::Purchases { purchases, truncated: false } = reply else {
+        panic!("Unexpected reply to `GetPurchases`");
+    };
+    let buyers: Vec<_> = purchases.iter().map(|(buyer, _)| *buyer).collect();
+    let mut sorted = buyers.clone();
+    sorted.sort();
+    assert_eq!(buyers, sorted);
+    let order_ids = |buyer: u64| purchases.iter().find(|(actor, _)| *actor == buyer.into()).map(|(_, purchases)| purchases.iter().map(|purchase| purchase.order_id).collect::<Vec<u128>>());
+    assert_eq!(order_ids(USERS[1]), Some(vec![0, 1, 2]));
+    assert_eq!(order_ids(USERS[2]), Some(vec![3]));
+    assert_eq!(order_ids(USERS[0]), None);
+}
+
+#[test]
+fn payout_keeps_refundable_proceeds() {
+    let system = System::new();
+    system.init_logger();
+    let market = Program::current_opt(&system);
+    let config = Config {
+        public_key: "public key".to_string(),
+        refund_window_blocks: 10,
+        ..Default::default()
+    };
+    let result = market.send(ADMIN, config);
+    assert!(!result.main_failed());
+
+    let price = 10_000_000_000_000;
+    market.add_product(ADMIN, "Product_#1".to_string(), 10, price, None);
+    system.mint_to(USERS[0], 3*price);
+    market.buy(USERS[0], price, "Product_#1".to_string(), 1, "delivery_address".to_string(), None);
+
+    // only refundable proceeds so far
+    let result = market.send(USERS[1], MarketAction::Payout { seller: ADMIN.into() });
+    assert!(result.contains(&(USERS[1], Err::<MarketEvent, _>(MarketError::NothingToPayout).encode())));
+
+    system.spend_blocks(11);
+    market.buy(USERS[0], 2*price, "Product_#1".to_string(), 2, "delivery_address".to_string(), None);
+    let result = market.send(USERS[1], MarketAction::Payout { seller: ADMIN.into() });
+    assert!(has_event(&result, USERS[1], &MarketEvent::PayoutSent { seller: ADMIN.into(), amount: price }));
+
+    // the order still in its window can be refunded
+    let result = market.send(USERS[0], MarketAction::CancelOrder { order_id: 1 });
+    assert!(has_event(&result, USERS[0], &MarketEvent::OrderCancelled { order_id: 1, refund: 2*price, refund_percent: 10_000 }));
+    let state: State = market.get_all_state().expect("Unexpected invalid state.");
+    assert!(state.pending_payouts.iter().all(|(_, amount)| *amount == 0));
+}